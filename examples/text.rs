@@ -1,3 +1,5 @@
+#![allow(clippy::disallowed_names)]
+
 extern crate current;
 
 use current::{ Current, CurrentGuard };
@@ -7,27 +9,25 @@ pub struct Foo {
 }
 
 fn print_foo() {
-    let foo = unsafe { &*Current::<Foo>::new() };
-    println!("{}", foo.text);
-    unsafe { &mut *Current::<Foo>::new() }.text = "world!".to_string();
+    let mut foo = unsafe { Current::<Foo>::new() };
+    println!("{}", foo.borrow().text);
+    foo.borrow_mut().text = "world!".to_string();
 }
 
 fn bar() {
     let mut bar = Foo { text: "good bye".to_string() };
-    let guard = CurrentGuard::new(&mut bar);
-    print_foo();
-    print_foo();
-    drop(guard);
+    CurrentGuard::scope(&mut bar, || {
+        print_foo();
+        print_foo();
+    });
 }
 
 fn main() {
     let mut foo = Foo { text: "hello".to_string() };
-    {
-        let guard = CurrentGuard::new(&mut foo);
+    CurrentGuard::scope(&mut foo, || {
         print_foo();
         print_foo();
         bar();
-        drop(guard);
-    }
+    });
     foo.text = "hi!".to_string();
 }
@@ -0,0 +1,149 @@
+//! Capturing current values to make them current again later.
+
+use std::any::Any;
+use std::panic::Location;
+
+use { ContextMap, Key, SlotKey, Slot, next_guard };
+
+/// A snapshot of current values on this thread, or in a `ContextMap`.
+///
+/// Entering the context makes the captured values current again,
+/// on top of the values current at that time.
+/// The snapshot does not keep the values alive:
+/// values whose guard has been dropped since are left out,
+/// both when entering and while inside the context.
+/// Captured values share their borrow state with the original guards,
+/// so they can not be mutably borrowed twice.
+///
+/// Unlike the guards, a context is not tied to the lifetime of the values,
+/// so it can be stored in callbacks that outlive them.
+/// Instead, whether each value is still alive is checked at runtime,
+/// and a context that outlives its values is entered as an empty one.
+pub struct Context {
+    map: ContextMap,
+    entries: Vec<(Key, Slot)>
+}
+
+impl Clone for Context {
+    fn clone(&self) -> Context {
+        Context { map: self.map.share(), entries: self.entries.clone() }
+    }
+}
+
+impl Default for Context {
+    fn default() -> Context {
+        Context::new()
+    }
+}
+
+impl Context {
+    /// Creates an empty context.
+    pub fn new() -> Context {
+        Context::new_in(&ContextMap::local())
+    }
+
+    /// Creates an empty context for the values of a map.
+    pub fn new_in(map: &ContextMap) -> Context {
+        Context { map: map.share(), entries: vec![] }
+    }
+
+    /// Captures all values current on this thread.
+    pub fn capture() -> Context {
+        Context::capture_in(&ContextMap::local())
+    }
+
+    /// Captures all values current in a map.
+    pub fn capture_in(map: &ContextMap) -> Context {
+        Context { map: map.share(), entries: map.live_slots() }
+    }
+
+    /// Adds the current object of type `T`, if one is set.
+    pub fn include<T>(self) -> Context where T: ?Sized + Any {
+        self.include_key(Key::of::<T, ()>(None))
+    }
+
+    /// Adds the current object of type `T` under a tag, if one is set.
+    pub fn include_tagged<T, Tag>(self) -> Context
+        where T: ?Sized + Any, Tag: Any
+    {
+        self.include_key(Key::of::<T, Tag>(None))
+    }
+
+    /// Adds the current object of type `T` under a key, if one is set.
+    pub fn include_named<T, K>(self, key: K) -> Context
+        where T: ?Sized + Any, K: Into<SlotKey>
+    {
+        self.include_key(Key::of::<T, ()>(Some(key.into())))
+    }
+
+    // Adds the innermost live slot for a key, replacing any captured before.
+    fn include_key(mut self, key: Key) -> Context {
+        if let Ok(slot) = self.map.live_slot(&key, Slot::clone) {
+            self.entries.retain(|(entry, _)| *entry != key);
+            self.entries.push((key, slot));
+        }
+        self
+    }
+
+    /// Returns the number of captured values that are still alive.
+    pub fn len(&self) -> usize {
+        self.entries.iter().filter(|&(_, slot)| slot.is_alive()).count()
+    }
+
+    /// Returns `true` if no captured value is still alive.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Makes the captured values current while calling a closure.
+    #[track_caller]
+    pub fn enter<F, R>(&self, f: F) -> R where F: FnOnce() -> R {
+        let site = Location::caller();
+        let _entered: Vec<Entered> = self.entries.iter()
+            .filter(|&(_, slot)| slot.is_alive())
+            .map(|(key, slot)| {
+                let guard = next_guard();
+                let slot = Slot { guard, site, ..slot.clone() };
+                self.map.push(key.clone(), slot);
+                Entered { map: self.map.share(), key: key.clone(), guard }
+            })
+            .collect();
+        f()
+    }
+}
+
+// A captured slot pushed by `Context::enter`, removed again when dropped.
+// Unlike `Installed`, this does not own the borrow state,
+// which the original guard checks when it is dropped.
+struct Entered {
+    map: ContextMap,
+    key: Key,
+    guard: u64
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        self.map.remove(&self.key, self.guard);
+    }
+}
+
+/// Wraps a closure to make the values current at this point
+/// current again whenever it is called.
+///
+/// See `Context`.
+pub fn bind<F, R>(mut f: F) -> impl FnMut() -> R where F: FnMut() -> R {
+    let context = Context::capture();
+    move || context.enter(&mut f)
+}
+
+/// Wraps a closure taking an argument, such as an event handler,
+/// to make the values current at this point current again
+/// whenever it is called.
+///
+/// See `Context`.
+pub fn bind_arg<F, A, R>(mut f: F) -> impl FnMut(A) -> R
+    where F: FnMut(A) -> R
+{
+    let context = Context::capture();
+    move |arg| context.enter(|| f(arg))
+}
@@ -0,0 +1,159 @@
+//! Current values for futures.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{ Context, Poll };
+
+use { ContextMap, CurrentGuard, CurrentStorage, Stores };
+
+// The maps of the tasks being polled on this thread, the innermost last.
+thread_local!(static TASK_MAPS: RefCell<Vec<ContextMap>>
+    = const { RefCell::new(vec![]) });
+
+/// A future that makes a value current while it is polled.
+///
+/// The value is current only for the duration of each `poll`,
+/// so it does not leak into other tasks run by the same thread,
+/// and it moves along with the future when an executor
+/// polls it on another thread.
+pub struct WithCurrent<F, T, Tag = ()> where T: Any, Tag: Any {
+    future: F,
+    val: T,
+    _tag: PhantomData<Tag>
+}
+
+impl<F, T> WithCurrent<F, T> where F: Future, T: Any {
+    /// Creates a future that makes a value current while it is polled.
+    pub fn new(future: F, val: T) -> WithCurrent<F, T> {
+        WithCurrent::new_tagged(future, val)
+    }
+}
+
+impl<F, T, Tag> WithCurrent<F, T, Tag> where F: Future, T: Any, Tag: Any {
+    /// Creates a future that makes a value current under a tag
+    /// while it is polled.
+    pub fn new_tagged(future: F, val: T) -> WithCurrent<F, T, Tag> {
+        WithCurrent { future, val, _tag: PhantomData }
+    }
+
+    /// Returns the future and the value.
+    pub fn into_inner(self) -> (F, T) {
+        (self.future, self.val)
+    }
+}
+
+// The value is never pinned, only the future.
+impl<F, T, Tag> Unpin for WithCurrent<F, T, Tag>
+    where F: Unpin, T: Any, Tag: Any {}
+
+impl<F, T, Tag> Future for WithCurrent<F, T, Tag>
+    where F: Future, T: Any, Tag: Any
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // Projects the pin to the future, which is never moved out of `self`
+        // while pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        CurrentGuard::<T, Tag>::scope_tagged(&mut this.val, || future.poll(cx))
+    }
+}
+
+/// Stores current values for each task, in the map of the innermost
+/// `TaskScope` being polled.
+///
+/// Outside of a task, no values are set, and setting one panics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TaskLocal;
+
+impl CurrentStorage for TaskLocal {
+    fn with_map<F, R>(&self, f: F) -> Option<R>
+        where F: FnOnce(&ContextMap) -> R
+    {
+        // The closure may poll other tasks, so do not keep `TASK_MAPS`
+        // borrowed while calling it.
+        let map = TASK_MAPS.with(|maps| {
+            maps.borrow().last().map(ContextMap::share)
+        });
+        map.map(|map| f(&map))
+    }
+}
+
+impl<T> Stores<T> for TaskLocal where T: ?Sized {}
+
+/// A future with a map of current values of its own,
+/// which `TaskLocal` uses while the future is polled.
+///
+/// Values set with `TaskLocal` storage stay in the map between polls,
+/// such as a `CurrentBox` kept across an `.await`.
+/// The map is not `Send`, so this works with executors
+/// that poll tasks on one thread, while `WithCurrent` works with any.
+pub struct TaskScope<F> {
+    future: F,
+    map: ContextMap
+}
+
+impl<F> TaskScope<F> where F: Future {
+    /// Creates a future with an empty map of current values.
+    pub fn new(future: F) -> TaskScope<F> {
+        TaskScope { future, map: ContextMap::new() }
+    }
+
+    /// Returns the map of current values of the task.
+    pub fn map(&self) -> &ContextMap {
+        &self.map
+    }
+}
+
+// Removes the map of a task when done polling it, even when panicking.
+struct EnteredTask;
+
+impl Drop for EnteredTask {
+    fn drop(&mut self) {
+        TASK_MAPS.with(|maps| maps.borrow_mut().pop());
+    }
+}
+
+impl<F> Future for TaskScope<F> where F: Future {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // Projects the pin to the future, which is never moved out of `self`
+        // while pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        TASK_MAPS.with(|maps| maps.borrow_mut().push(this.map.share()));
+        let _entered = EnteredTask;
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        future.poll(cx)
+    }
+}
+
+/// Adds `with_current` and `task_scope` to futures.
+pub trait WithCurrentExt: Future + Sized {
+    /// Makes a value current while the future is polled.
+    ///
+    /// See `WithCurrent`.
+    fn with_current<T>(self, val: T) -> WithCurrent<Self, T> where T: Any {
+        WithCurrent::new(self, val)
+    }
+
+    /// Makes a value current under a tag while the future is polled.
+    fn with_current_tagged<T, Tag>(self, val: T) -> WithCurrent<Self, T, Tag>
+        where T: Any, Tag: Any
+    {
+        WithCurrent::new_tagged(self, val)
+    }
+
+    /// Gives the future a map of current values of its own.
+    ///
+    /// See `TaskScope`.
+    fn task_scope(self) -> TaskScope<Self> {
+        TaskScope::new(self)
+    }
+}
+
+impl<F> WithCurrentExt for F where F: Future {}
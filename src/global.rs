@@ -0,0 +1,99 @@
+//! Process-wide current values, shared by all threads.
+
+use std::any::Any;
+use std::marker::PhantomData;
+
+use { ContextMap, Current, CurrentStorage, NoCurrentError, SharedGuard };
+use Stores;
+
+/// Stores current values in one map for the process, shared by all threads.
+///
+/// The current value of a type is the one set most recently
+/// by a guard that is still alive, no matter which thread set it.
+/// Dropping a guard removes only its own value, so guards on different
+/// threads may be dropped in any order.
+/// Dropping a guard blocks until other threads are done borrowing its value,
+/// so it must not be dropped while borrowing the value on the same thread,
+/// since that would never return.
+///
+/// Only values that are `Send` and `Sync` can be set.
+/// They are borrowed by the same rules as other current values,
+/// so a mutable borrow panics while another thread borrows the value.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Global;
+
+impl CurrentStorage for Global {
+    fn with_map<F, R>(&self, f: F) -> Option<R>
+        where F: FnOnce(&ContextMap) -> R
+    {
+        Some(f(&ContextMap::global()))
+    }
+}
+
+impl<T> Stores<T> for Global where T: ?Sized + Send + Sync {}
+
+/// Puts back the previous global current pointer.
+///
+/// This is a `SharedGuard` with `Global` storage, see `Global`.
+pub struct GlobalCurrentGuard<'a, T>
+    where T: ?Sized + Any + Send + Sync
+{
+    _guard: SharedGuard<'a, T, (), Global>
+}
+
+impl<'a, T> GlobalCurrentGuard<'a, T>
+    where T: ?Sized + Any + Send + Sync
+{
+    /// Makes a value current for all threads while calling a closure.
+    ///
+    /// See `CurrentGuard::scope`.
+    pub fn scope<F, R>(val: &T, f: F) -> R where F: FnOnce() -> R {
+        let _guard = unsafe { GlobalCurrentGuard::new(val) };
+        f()
+    }
+
+    /// Creates a new global current guard.
+    ///
+    /// # Safety
+    ///
+    /// The guard must be dropped before the borrow of the value ends.
+    /// Leaking it, for example with `mem::forget`, leaves a dangling
+    /// pointer to the value in the global current values.
+    /// The guard must not be dropped from within `GlobalCurrent::with`
+    /// while accessing its own value, since that would never return.
+    pub unsafe fn new(val: &T) -> GlobalCurrentGuard<'_, T> {
+        GlobalCurrentGuard { _guard: SharedGuard::new_in(&Global, val) }
+    }
+}
+
+/// The global current value of a type, shared by all threads.
+///
+/// This is `Current` with `Global` storage.
+/// Only shared references are handed out,
+/// so use interior mutability for values that change.
+pub struct GlobalCurrent<T>(PhantomData<T>)
+    where T: ?Sized + Any + Send + Sync;
+
+impl<T> GlobalCurrent<T> where T: ?Sized + Any + Send + Sync {
+    /// Calls a closure with a shared reference to the global current object.
+    ///
+    /// Returns `None` if no global current object of type `T` is set.
+    pub fn with<F, R>(f: F) -> Option<R> where F: FnOnce(&T) -> R {
+        Current::<T, (), Global>::with(f)
+    }
+
+    /// Calls a closure with a shared reference to the global current object.
+    ///
+    /// Returns an error if no global current object of type `T` is set.
+    pub fn try_with<F, R>(f: F) -> Result<R, NoCurrentError>
+        where F: FnOnce(&T) -> R
+    {
+        Current::<T, (), Global>::try_with(f)
+    }
+
+    /// Returns the number of global objects of type `T` set,
+    /// counting the current one and the ones it shadows.
+    pub fn depth() -> usize {
+        Current::<T, (), Global>::depth()
+    }
+}
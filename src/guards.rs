@@ -0,0 +1,354 @@
+//! Making several values current together.
+
+use std::any::Any;
+use std::marker::PhantomData;
+
+use { ContextMap, CurrentBox, CurrentGuard, CurrentStorage, Installed };
+use { SharedGuard, Stores, ThreadLocal, storage_map };
+
+/// Puts back the previous current pointers of several values,
+/// in the reverse order they were made current.
+///
+/// The `S` type chooses where the values are stored, see `CurrentStorage`.
+pub struct CurrentGuards<'a, S = ThreadLocal> where S: CurrentStorage {
+    map: ContextMap,
+    installed: Vec<Installed>,
+    _vals: PhantomData<&'a mut ()>,
+    _storage: PhantomData<S>
+}
+
+impl<'a> CurrentGuards<'a> {
+    /// Makes a tuple of values current while calling a closure.
+    ///
+    /// See `CurrentGuard::scope`.
+    #[track_caller]
+    pub fn scope<V, F, R>(vals: V, f: F) -> R
+        where V: GuardTuple<'a>, F: FnOnce() -> R
+    {
+        CurrentGuards::<ThreadLocal>::scope_stored(vals, f)
+    }
+
+    /// Creates a guard making a tuple of values current,
+    /// such as `(&mut window, &mut device)`.
+    ///
+    /// # Safety
+    ///
+    /// See `CurrentGuard::new`.
+    #[track_caller]
+    pub unsafe fn new<V>(vals: V) -> CurrentGuards<'a> where V: GuardTuple<'a> {
+        CurrentGuards::new_stored(vals)
+    }
+}
+
+impl<'a, S> CurrentGuards<'a, S> where S: CurrentStorage {
+    /// Makes a tuple of values current in a storage while calling a closure.
+    ///
+    /// See `CurrentGuard::scope`.
+    #[track_caller]
+    pub fn scope_stored<V, F, R>(vals: V, f: F) -> R
+        where S: Default, V: GuardTuple<'a, S>, F: FnOnce() -> R
+    {
+        CurrentGuards::scope_in(&S::default(), vals, f)
+    }
+
+    /// Creates a guard making a tuple of values current in a storage.
+    ///
+    /// # Safety
+    ///
+    /// See `CurrentGuard::new`.
+    #[track_caller]
+    pub unsafe fn new_stored<V>(vals: V) -> CurrentGuards<'a, S>
+        where S: Default, V: GuardTuple<'a, S>
+    {
+        CurrentGuards::new_in(&S::default(), vals)
+    }
+
+    /// Makes a tuple of values current in a storage instance,
+    /// such as a `ContextMap`, while calling a closure.
+    ///
+    /// See `CurrentGuard::scope`.
+    #[track_caller]
+    pub fn scope_in<V, F, R>(storage: &S, vals: V, f: F) -> R
+        where V: GuardTuple<'a, S>, F: FnOnce() -> R
+    {
+        let _guards = unsafe { CurrentGuards::new_in(storage, vals) };
+        f()
+    }
+
+    /// Creates a guard making a tuple of values current
+    /// in a storage instance.
+    ///
+    /// # Safety
+    ///
+    /// See `CurrentGuard::new`.
+    #[track_caller]
+    pub unsafe fn new_in<V>(storage: &S, vals: V) -> CurrentGuards<'a, S>
+        where V: GuardTuple<'a, S>
+    {
+        let mut guards = CurrentGuards {
+            map: storage_map(storage),
+            installed: vec![],
+            _vals: PhantomData,
+            _storage: PhantomData
+        };
+        vals.install(&mut guards);
+        guards
+    }
+
+    // Makes a value current, to be put back before the values before it.
+    #[track_caller]
+    #[allow(trivial_casts)]
+    fn push<T>(&mut self, val: &'a mut T)
+        where T: ?Sized + Any, S: Stores<T>
+    {
+        let ptr = val as *mut T;
+        let installed = Installed::new_in::<T, ()>(&self.map, ptr, false, None);
+        self.installed.push(installed);
+    }
+}
+
+impl<'a, S> Drop for CurrentGuards<'a, S> where S: CurrentStorage {
+    fn drop(&mut self) {
+        while let Some(installed) = self.installed.pop() {
+            drop(installed);
+        }
+    }
+}
+
+/// A tuple of mutable references that can be made current together
+/// in a storage.
+pub trait GuardTuple<'a, S = ThreadLocal> where S: CurrentStorage {
+    /// Makes the values current, from the first to the last,
+    /// adding them to the guards.
+    fn install(self, guards: &mut CurrentGuards<'a, S>);
+}
+
+macro_rules! guard_tuple {
+    ($($name:ident),+) => {
+        impl<'a, S, $($name),+> GuardTuple<'a, S> for ($(&'a mut $name,)+)
+            where S: CurrentStorage $(+ Stores<$name>)+,
+                  $($name: ?Sized + Any),+
+        {
+            #[track_caller]
+            #[allow(non_snake_case)]
+            fn install(self, guards: &mut CurrentGuards<'a, S>) {
+                let ($($name,)+) = self;
+                $(guards.push($name);)+
+            }
+        }
+    }
+}
+
+guard_tuple!(A);
+guard_tuple!(A, B);
+guard_tuple!(A, B, C);
+guard_tuple!(A, B, C, D);
+guard_tuple!(A, B, C, D, E);
+guard_tuple!(A, B, C, D, E, F);
+guard_tuple!(A, B, C, D, E, F, G);
+guard_tuple!(A, B, C, D, E, F, G, H);
+
+/// Makes several values current while running a block,
+/// putting back the previous current pointers in reverse order.
+///
+/// This is shorthand for `CurrentGuards::scope`, and evaluates to the value
+/// of the block. The block runs in a closure, so `return` and `?`
+/// leave the block rather than the enclosing function.
+///
+/// ```
+/// #[macro_use]
+/// extern crate current;
+///
+/// use current::Current;
+///
+/// struct Window(u32);
+/// struct Device(u32);
+///
+/// fn main() {
+///     let mut window = Window(1);
+///     let mut device = Device(2);
+///     let id = set_currents!((window, device) => {
+///         Current::<Device>::with(|device| device.0)
+///     });
+///     assert_eq!(id, Some(2));
+///     assert_eq!(Current::<Window>::with(|window| window.0), None);
+/// }
+/// ```
+#[macro_export]
+macro_rules! set_currents {
+    (($($val:expr),+ $(,)?) => $body:block) => {
+        $crate::CurrentGuards::scope(($(&mut $val,)+), || $body)
+    }
+}
+
+mod sealed {
+    // Keeps `Guard` from being implemented outside of this crate.
+    pub trait Sealed {}
+}
+
+/// A guard that can be kept in a `GuardStack`.
+///
+/// This trait is sealed, so only the guards of this crate implement it.
+pub trait Guard: sealed::Sealed {}
+
+impl<'a, T, Tag, S> sealed::Sealed for CurrentGuard<'a, T, Tag, S>
+    where T: ?Sized + Any, Tag: Any, S: CurrentStorage {}
+impl<'a, T, Tag, S> Guard for CurrentGuard<'a, T, Tag, S>
+    where T: ?Sized + Any, Tag: Any, S: CurrentStorage {}
+
+impl<'a, T, Tag, S> sealed::Sealed for SharedGuard<'a, T, Tag, S>
+    where T: ?Sized + Any, Tag: Any, S: CurrentStorage {}
+impl<'a, T, Tag, S> Guard for SharedGuard<'a, T, Tag, S>
+    where T: ?Sized + Any, Tag: Any, S: CurrentStorage {}
+
+impl<T, Tag, S> sealed::Sealed for CurrentBox<T, Tag, S>
+    where T: ?Sized + Any, Tag: Any, S: CurrentStorage {}
+impl<T, Tag, S> Guard for CurrentBox<T, Tag, S>
+    where T: ?Sized + Any, Tag: Any, S: CurrentStorage {}
+
+impl<'a, S> sealed::Sealed for CurrentGuards<'a, S> where S: CurrentStorage {}
+impl<'a, S> Guard for CurrentGuards<'a, S> where S: CurrentStorage {}
+
+impl<'a, S> sealed::Sealed for GuardStack<'a, S> where S: CurrentStorage {}
+impl<'a, S> Guard for GuardStack<'a, S> where S: CurrentStorage {}
+
+// An entry of any type, kept in a `GuardStack` to be dropped later.
+trait Entry {}
+
+impl<T> Entry for T {}
+
+/// A stack of current values and guards of different types,
+/// for setting a number of values decided at runtime.
+///
+/// Entries are dropped in the reverse order they were pushed,
+/// when popped or when the stack is dropped.
+///
+/// The `S` type chooses where the values pushed with `push`
+/// and `push_tagged` are stored, see `CurrentStorage`.
+pub struct GuardStack<'a, S = ThreadLocal> where S: CurrentStorage {
+    map: ContextMap,
+    entries: Vec<Box<dyn Entry + 'a>>,
+    _storage: PhantomData<S>
+}
+
+impl<'a> GuardStack<'a> {
+    /// Calls a closure with an empty stack,
+    /// which is dropped with the values still on it when the closure returns.
+    ///
+    /// See `CurrentGuard::scope`.
+    pub fn scope<F, R>(f: F) -> R where F: FnOnce(&mut GuardStack<'a>) -> R {
+        GuardStack::<ThreadLocal>::scope_stored(f)
+    }
+
+    /// Creates an empty stack.
+    ///
+    /// # Safety
+    ///
+    /// The stack must be dropped before the borrows of the values
+    /// pushed on it end. See `CurrentGuard::new`.
+    pub unsafe fn new() -> GuardStack<'a> {
+        GuardStack::new_stored()
+    }
+}
+
+impl<'a, S> GuardStack<'a, S> where S: CurrentStorage {
+    /// Calls a closure with an empty stack for a storage.
+    ///
+    /// See `GuardStack::scope`.
+    #[track_caller]
+    pub fn scope_stored<F, R>(f: F) -> R
+        where S: Default, F: FnOnce(&mut GuardStack<'a, S>) -> R
+    {
+        GuardStack::scope_in(&S::default(), f)
+    }
+
+    /// Creates an empty stack for a storage.
+    ///
+    /// # Safety
+    ///
+    /// See `GuardStack::new`.
+    #[track_caller]
+    pub unsafe fn new_stored() -> GuardStack<'a, S> where S: Default {
+        GuardStack::new_in(&S::default())
+    }
+
+    /// Calls a closure with an empty stack for a storage instance,
+    /// such as a `ContextMap`.
+    ///
+    /// See `GuardStack::scope`.
+    #[track_caller]
+    pub fn scope_in<F, R>(storage: &S, f: F) -> R
+        where F: FnOnce(&mut GuardStack<'a, S>) -> R
+    {
+        let mut stack = unsafe { GuardStack::new_in(storage) };
+        f(&mut stack)
+    }
+
+    /// Creates an empty stack for a storage instance.
+    ///
+    /// # Safety
+    ///
+    /// See `GuardStack::new`.
+    #[track_caller]
+    pub unsafe fn new_in(storage: &S) -> GuardStack<'a, S> {
+        GuardStack {
+            map: storage_map(storage),
+            entries: vec![],
+            _storage: PhantomData
+        }
+    }
+
+    /// Makes a value current until it is popped.
+    #[track_caller]
+    pub fn push<T>(&mut self, val: &'a mut T)
+        where T: ?Sized + Any, S: Stores<T>
+    {
+        self.push_tagged::<T, ()>(val)
+    }
+
+    /// Makes a value current under a tag until it is popped.
+    #[track_caller]
+    #[allow(trivial_casts)]
+    pub fn push_tagged<T, Tag>(&mut self, val: &'a mut T)
+        where T: ?Sized + Any, Tag: Any, S: Stores<T>
+    {
+        let ptr = val as *mut T;
+        let installed = Installed::new_in::<T, Tag>(&self.map, ptr, false,
+                                                    None);
+        self.push_entry(installed);
+    }
+
+    /// Keeps a guard until it is popped, such as a `CurrentBox`
+    /// or a `SharedGuard`.
+    pub fn push_guard<G>(&mut self, guard: G) where G: Guard + 'a {
+        self.push_entry(guard);
+    }
+
+    // Keeps an entry until it is popped.
+    fn push_entry<E>(&mut self, entry: E) where E: 'a {
+        self.entries.push(Box::new(entry));
+    }
+
+    /// Drops the innermost entry, putting back the previous current value.
+    ///
+    /// Returns `false` if the stack is empty.
+    pub fn pop(&mut self) -> bool {
+        self.entries.pop().is_some()
+    }
+
+    /// Returns the number of entries on the stack.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if the stack has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<'a, S> Drop for GuardStack<'a, S> where S: CurrentStorage {
+    fn drop(&mut self) {
+        while self.pop() {}
+    }
+}
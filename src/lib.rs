@@ -1,118 +1,1869 @@
 #![deny(missing_docs)]
-#![feature(core)]
 
 //! A library for setting current values for stack scope,
 //! such as application structure.
 
-use std::cell::RefCell;
-use std::any::{ TypeId, Any };
-use std::collections::HashMap;
-use std::collections::hash_map::Entry::{ Occupied, Vacant };
+use std::any::{ type_name, TypeId, Any };
+use std::borrow::Cow;
+use std::collections::{ HashMap, HashSet };
+use std::error::Error;
+use std::fmt;
 use std::ops::{ Deref, DerefMut };
 use std::marker::PhantomData;
+use std::mem;
+use std::panic::Location;
+use std::rc::Rc;
+use std::sync::atomic::{ AtomicBool, AtomicIsize, AtomicU64, AtomicU8 };
+use std::sync::atomic::Ordering;
+use std::sync::{ Arc, Mutex, MutexGuard, OnceLock, PoisonError };
+use std::thread::{ self, ThreadId };
+use std::vec;
 
-// Stores the current pointers for concrete types.
-thread_local!(static KEY_CURRENT: RefCell<HashMap<TypeId, usize>>
-    = RefCell::new(HashMap::new()));
+pub use context::{ Context, bind, bind_arg };
+pub use future::{ TaskLocal, TaskScope, WithCurrent, WithCurrentExt };
+pub use global::{ Global, GlobalCurrent, GlobalCurrentGuard };
+pub use guards::{ CurrentGuards, Guard, GuardStack, GuardTuple };
+pub use spawn::{ Currents, spawn_with_currents, spawn_scoped_with_currents };
+pub use with::{ CurrentsFn, try_with_currents, with_currents };
 
-/// Puts back the previous current pointer.
-pub struct CurrentGuard<'a, T> where T: Any {
-    _val: &'a mut T,
-    old_ptr: Option<usize>
+mod context;
+mod future;
+mod global;
+mod guards;
+mod spawn;
+mod with;
+
+// Stores the current values of this thread.
+thread_local!(static KEY_CURRENT: ContextMap = ContextMap::new());
+
+// Stores the current values shared by all threads, see `Global`.
+static GLOBAL_CURRENT: OnceLock<Arc<Registry>> = OnceLock::new();
+
+// Hands out identities for guards,
+// which are unique across threads for the process-global map.
+static NEXT_GUARD: AtomicU64 = AtomicU64::new(0);
+
+// Borrow state of a current value, shared with the borrows handed out.
+type BorrowFlag = Arc<BorrowState>;
+
+// Positive values of `flag` count shared borrows,
+// `WRITING` marks an exclusive one.
+// The state is shared between threads for values in the process-global map.
+// `views` update the pointers of views of the value,
+// see `CurrentBuilder::view`.
+struct BorrowState {
+    flag: AtomicIsize,
+    views: Mutex<Vec<Box<dyn Fn() + Send + Sync>>>
 }
 
-#[allow(trivial_casts)]
-impl<'a, T> CurrentGuard<'a, T> where T: Any {
-    /// Creates a new current guard.
-    pub fn new(val: &mut T) -> CurrentGuard<T> {
-        let id = TypeId::of::<T>();
-        let ptr = val as *mut T as usize;
-        let old_ptr = KEY_CURRENT.with(|current| {
-            match current.borrow_mut().entry(id) {
-                Occupied(mut entry) => Some(entry.insert(ptr)),
-                Vacant(entry) => {
-                    entry.insert(ptr);
-                    None
-                }
+impl BorrowState {
+    fn new() -> BorrowFlag {
+        let flag = AtomicIsize::new(UNUSED);
+        Arc::new(BorrowState { flag, views: Mutex::default() })
+    }
+
+    fn get(&self) -> isize { self.flag.load(Ordering::SeqCst) }
+
+    // Starts a shared borrow.
+    fn acquire(&self, name: &Name) {
+        let mut flag = self.get();
+        loop {
+            if flag == WRITING {
+                panic!("Current {} is already mutably borrowed", name);
             }
-        });
-        CurrentGuard { old_ptr: old_ptr, _val: val }
+            match self.flag.compare_exchange(flag, flag + 1,
+                                             Ordering::SeqCst,
+                                             Ordering::SeqCst) {
+                Ok(_) => return,
+                Err(now) => flag = now
+            }
+        }
+    }
+
+    // Starts an exclusive borrow.
+    fn acquire_mut(&self, name: &Name) {
+        match self.flag.compare_exchange(UNUSED, WRITING,
+                                         Ordering::SeqCst,
+                                         Ordering::SeqCst) {
+            Ok(_) => {}
+            Err(WRITING) => {
+                panic!("Current {} is already mutably borrowed", name)
+            }
+            Err(_) => panic!("Current {} is already borrowed", name)
+        }
+    }
+
+    // Ends a shared borrow.
+    fn release(&self) {
+        self.flag.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // Ends an exclusive borrow.
+    // The value may have changed where its views point, so update them first.
+    fn release_mut(&self) {
+        for update in lock(&self.views).iter() {
+            update();
+        }
+        self.flag.store(UNUSED, Ordering::SeqCst);
+    }
+
+    // Waits for borrows on other threads to end.
+    fn wait_unused(&self) {
+        while self.get() != UNUSED {
+            thread::yield_now();
+        }
     }
 }
 
-impl<'a, T> Drop for CurrentGuard<'a, T> where T: Any {
-    fn drop(&mut self) {
-        let id = TypeId::of::<T>();
-        match self.old_ptr {
+// Locks a mutex, ignoring poisoning,
+// since the data is never left half updated by a panic.
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+const UNUSED: isize = 0;
+const WRITING: isize = -1;
+
+/// A key chosen at runtime to tell apart current values of the same type.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SlotKey {
+    /// A string key.
+    Str(Cow<'static, str>),
+    /// An integer key.
+    Int(u64),
+}
+
+impl From<&'static str> for SlotKey {
+    fn from(key: &'static str) -> SlotKey { SlotKey::Str(Cow::Borrowed(key)) }
+}
+
+impl From<String> for SlotKey {
+    fn from(key: String) -> SlotKey { SlotKey::Str(Cow::Owned(key)) }
+}
+
+impl From<u64> for SlotKey {
+    fn from(key: u64) -> SlotKey { SlotKey::Int(key) }
+}
+
+impl fmt::Display for SlotKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SlotKey::Str(ref key) => write!(f, "{}", key),
+            SlotKey::Int(key) => write!(f, "{}", key)
+        }
+    }
+}
+
+// Identifies the stack of current values for a type, tag and slot key.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Key {
+    ty: TypeId,
+    tag: TypeId,
+    slot_key: Option<SlotKey>
+}
+
+impl Key {
+    fn of<T: ?Sized + Any, Tag: Any>(slot_key: Option<SlotKey>) -> Key {
+        Key { ty: TypeId::of::<T>(), tag: TypeId::of::<Tag>(), slot_key }
+    }
+}
+
+// Names a type, tag and slot key in messages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Name {
+    type_name: &'static str,
+    tag_name: Option<&'static str>,
+    slot_key: Option<SlotKey>
+}
+
+impl Name {
+    fn of<T: ?Sized + Any, Tag: Any>(slot_key: Option<SlotKey>) -> Name {
+        let untagged = TypeId::of::<Tag>() == TypeId::of::<()>();
+        Name {
+            type_name: type_name::<T>(),
+            tag_name: if untagged { None } else { Some(type_name::<Tag>()) },
+            slot_key
+        }
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`", self.type_name)?;
+        if let Some(tag_name) = self.tag_name {
+            write!(f, " tagged `{}`", tag_name)?;
+        }
+        if let Some(ref slot_key) = self.slot_key {
+            write!(f, " named `{}`", slot_key)?;
+        }
+        Ok(())
+    }
+}
+
+// A pointer to a current value, which is updated for views.
+// Pointers are only shared between threads in the process-global map,
+// which takes values that are `Send` and `Sync`, see `Stores`.
+struct SlotPtr<T: ?Sized>(Mutex<*mut T>);
+
+unsafe impl<T: ?Sized> Send for SlotPtr<T> {}
+unsafe impl<T: ?Sized> Sync for SlotPtr<T> {}
+
+impl<T: ?Sized> SlotPtr<T> {
+    fn new(ptr: *mut T) -> SlotPtr<T> { SlotPtr(Mutex::new(ptr)) }
+
+    fn get(&self) -> *mut T { *lock(&self.0) }
+
+    fn set(&self, ptr: *mut T) { *lock(&self.0) = ptr }
+}
+
+// A current pointer together with its borrow state
+// and the guard that set it.
+// The pointer is stored as a `SlotPtr<T>`, which may be a fat pointer,
+// so that views can be updated.
+// Values set from shared references are `read_only`.
+// `alive` is cleared when the guard that set the value is dropped,
+// so copies of the stack can tell which values are gone.
+// Slots reinstalled from a `Context` share `alive` with the original.
+#[derive(Clone)]
+struct Slot {
+    ptr: Arc<dyn Any + Send + Sync>,
+    name: Arc<Name>,
+    read_only: bool,
+    borrow: BorrowFlag,
+    alive: Arc<AtomicBool>,
+    guard: u64,
+    site: &'static Location<'static>
+}
+
+impl Slot {
+    fn ptr<T: ?Sized + Any>(&self) -> *mut T {
+        self.ptr.downcast_ref::<SlotPtr<T>>()
+            .expect("Current pointer of wrong type")
+            .get()
+    }
+
+    fn is_alive(&self) -> bool { self.alive.load(Ordering::SeqCst) }
+}
+
+/// What to do when a `CurrentGuard` is dropped while a guard
+/// for the same type created after it is still alive.
+///
+/// In every case the registry is repaired by removing only the
+/// value of the dropped guard, so the remaining guards stay current
+/// in the order they were created.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutOfOrder {
+    /// Panic with the creation sites of both guards.
+    Panic,
+    /// Print the creation sites of both guards to standard error.
+    Log,
+    /// Repair the registry silently.
+    Repair,
+}
+
+// Policy for out of order drops, `DEFAULT_ORDER` until set.
+static OUT_OF_ORDER: AtomicU8 = AtomicU8::new(DEFAULT_ORDER);
+
+const DEFAULT_ORDER: u8 = 0;
+
+impl OutOfOrder {
+    /// Returns the policy for out of order drops.
+    ///
+    /// Defaults to `Panic` in debug builds and `Log` in release builds.
+    pub fn get() -> OutOfOrder {
+        match OUT_OF_ORDER.load(Ordering::Relaxed) {
+            1 => OutOfOrder::Panic,
+            2 => OutOfOrder::Log,
+            3 => OutOfOrder::Repair,
+            _ if cfg!(debug_assertions) => OutOfOrder::Panic,
+            _ => OutOfOrder::Log
+        }
+    }
+
+    /// Sets the policy for out of order drops on all threads.
+    pub fn set(policy: OutOfOrder) {
+        let val = match policy {
+            OutOfOrder::Panic => 1,
+            OutOfOrder::Log => 2,
+            OutOfOrder::Repair => 3
+        };
+        OUT_OF_ORDER.store(val, Ordering::Relaxed);
+    }
+}
+
+// Hands out a new guard identity.
+fn next_guard() -> u64 {
+    NEXT_GUARD.fetch_add(1, Ordering::Relaxed)
+}
+
+// The stacks of a `ContextMap`.
+#[derive(Default)]
+struct Registry {
+    // The stacks of current pointers for concrete types, tags and keys.
+    // The innermost current value is at the end of each stack.
+    // Stacks are removed when they become empty.
+    stacks: Mutex<HashMap<Key, Vec<Slot>>>,
+    // The types and tags whose stacks have become empty,
+    // to tell apart types that were never set from those that were removed.
+    // Slot keys are left out, so this does not grow with the keys used.
+    removed: Mutex<HashSet<(TypeId, TypeId)>>
+}
+
+/// A set of current values that is passed around explicitly,
+/// instead of being kept for each thread.
+///
+/// Values are set and borrowed by the same rules as with `CurrentGuard`
+/// and `Current`, which use one such map for each thread.
+/// The methods of the map cover values set under their own type,
+/// without a tag or a runtime key.
+/// To use the map with the guards and `Current`,
+/// pass it as their storage, such as with `CurrentGuard::scope_in`.
+///
+/// A map is not `Clone`, since a copy would share its values.
+/// Pass it by reference instead.
+pub struct ContextMap {
+    registry: Arc<Registry>,
+    // Whether this is the process-global map, see `Global`.
+    shared: bool,
+    // Keeps the map on the thread it was created on,
+    // since it takes values that are not `Send`.
+    _local: PhantomData<Rc<()>>
+}
+
+impl ContextMap {
+    /// Creates an empty map.
+    // Not `Default`, so that static methods such as `Current::with`
+    // can not be called with a map as storage, which would look up
+    // values in a new, empty map.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> ContextMap {
+        ContextMap {
+            registry: Arc::default(),
+            shared: false,
+            _local: PhantomData
+        }
+    }
+
+    /// Makes a value current in the map while calling a closure.
+    ///
+    /// See `CurrentGuard::scope`.
+    #[track_caller]
+    pub fn scope<T, F, R>(&self, val: &mut T, f: F) -> R
+        where T: ?Sized + Any, F: FnOnce() -> R
+    {
+        let _guard = unsafe { self.insert(val) };
+        f()
+    }
+
+    /// Makes a value current in the map until the guard is dropped.
+    ///
+    /// # Safety
+    ///
+    /// See `CurrentGuard::new`.
+    #[track_caller]
+    #[allow(trivial_casts)]
+    pub unsafe fn insert<'a, T>(&self, val: &'a mut T) -> MapGuard<'a, T>
+        where T: ?Sized + Any
+    {
+        let ptr = val as *mut T;
+        let map = storage_map(self);
+        MapGuard {
+            _installed: Installed::new_in::<T, ()>(&map, ptr, false, None),
+            _val: PhantomData
+        }
+    }
+
+    /// Calls a closure with a shared reference to the current object
+    /// of type `T` in the map.
+    ///
+    /// See `Current::with`.
+    pub fn with<T, F, R>(&self, f: F) -> Option<R>
+        where T: ?Sized + Any, F: FnOnce(&T) -> R
+    {
+        self.try_with(f).ok()
+    }
+
+    /// Calls a closure with a mutable reference to the current object
+    /// of type `T` in the map.
+    ///
+    /// See `Current::with_mut`.
+    pub fn with_mut<T, F, R>(&self, f: F) -> Option<R>
+        where T: ?Sized + Any, F: FnOnce(&mut T) -> R
+    {
+        self.try_with_mut(f).ok()
+    }
+
+    /// Calls a closure with a shared reference to the current object
+    /// of type `T` in the map.
+    ///
+    /// See `Current::try_with`.
+    pub fn try_with<T, F, R>(&self, f: F) -> Result<R, NoCurrentError>
+        where T: ?Sized + Any, F: FnOnce(&T) -> R
+    {
+        let val = self.try_slot::<T, (), _, _>(None, Ref::<T>::new)?;
+        Ok(f(&val))
+    }
+
+    /// Calls a closure with a mutable reference to the current object
+    /// of type `T` in the map.
+    ///
+    /// See `Current::try_with_mut`.
+    pub fn try_with_mut<T, F, R>(&self, f: F) -> Result<R, NoCurrentError>
+        where T: ?Sized + Any, F: FnOnce(&mut T) -> R
+    {
+        let mut val = self.try_slot::<T, (), _, _>(None, RefMut::<T>::new)?;
+        Ok(f(&mut val))
+    }
+
+    /// Returns the number of objects of type `T` set in the map,
+    /// counting the current one and the ones it shadows.
+    pub fn depth<T>(&self) -> usize where T: ?Sized + Any {
+        self.stack::<T, ()>(None).len()
+    }
+
+    // Returns the map of this thread.
+    fn local() -> ContextMap {
+        KEY_CURRENT.with(ContextMap::share)
+    }
+
+    // Returns the map shared by all threads.
+    fn global() -> ContextMap {
+        ContextMap {
+            registry: GLOBAL_CURRENT.get_or_init(Arc::default).clone(),
+            shared: true,
+            _local: PhantomData
+        }
+    }
+
+    // Returns a handle to the same values,
+    // for guards and contexts to keep.
+    fn share(&self) -> ContextMap {
+        ContextMap {
+            registry: self.registry.clone(),
+            shared: self.shared,
+            _local: PhantomData
+        }
+    }
+
+    fn stacks(&self) -> MutexGuard<'_, HashMap<Key, Vec<Slot>>> {
+        lock(&self.registry.stacks)
+    }
+
+    // Pushes a slot on top of the stack for a key.
+    fn push(&self, key: Key, slot: Slot) {
+        self.stacks().entry(key).or_default().push(slot);
+    }
+
+    // Marks a value as gone.
+    // Values are borrowed while holding the lock on the stacks,
+    // so no borrow of the value starts after this.
+    fn retire(&self, alive: &AtomicBool) {
+        let _stacks = self.stacks();
+        alive.store(false, Ordering::SeqCst);
+    }
+
+    // Removes the slot set by a guard from the stack for a key.
+    // Returns the creation site of the innermost guard
+    // if it was not the one removed.
+    // Guards on different threads share the process-global map,
+    // so they may be dropped in any order there.
+    fn remove(&self, key: &Key, guard: u64)
+        -> Option<&'static Location<'static>>
+    {
+        let mut stacks = self.stacks();
+        let stack = stacks.get_mut(key)?;
+        let index = stack.iter().rposition(|slot| slot.guard == guard)?;
+        let top = stack.last().unwrap().site;
+        let removed = stack.remove(index);
+        // Reinstalling the same value from a `Context` is not a guard.
+        let out_of_order = !self.shared && stack[index..].iter()
+            .any(|slot| !Arc::ptr_eq(&slot.alive, &removed.alive));
+        if stack.is_empty() {
+            stacks.remove(key);
+            lock(&self.registry.removed).insert((key.ty, key.tag));
+        }
+        if out_of_order { Some(top) } else { None }
+    }
+
+    // Calls a function with the innermost slot for a key
+    // whose value is still alive, holding the lock on the stacks,
+    // so the value can be borrowed before it is removed.
+    // Returns whether a value was removed if there is none.
+    // Values reinstalled from a `Context` may be gone already.
+    fn live_slot<F, R>(&self, key: &Key, f: F) -> Result<R, bool>
+        where F: FnOnce(&Slot) -> R
+    {
+        let stacks = self.stacks();
+        match stacks.get(key) {
+            Some(stack) => stack.iter().rev().find(|slot| slot.is_alive())
+                .map(f).ok_or(true),
             None => {
-                KEY_CURRENT.with(|current| {
-                    current.borrow_mut().remove(&id);
-                });
-                return;
+                let removed = lock(&self.registry.removed);
+                Err(removed.contains(&(key.ty, key.tag)))
             }
-            Some(old_ptr) => {
-                KEY_CURRENT.with(|current| {
-                    match current.borrow_mut().entry(id) {
-                        Occupied(mut entry) => { entry.insert(old_ptr); }
-                        Vacant(entry) => { entry.insert(old_ptr); }
-                    };
-                });
+        }
+    }
+
+    // Copies the innermost live slot for every key.
+    fn live_slots(&self) -> Vec<(Key, Slot)> {
+        self.stacks().iter()
+            .filter_map(|(key, stack)| {
+                let slot = stack.iter().rev().find(|slot| slot.is_alive())?;
+                Some((key.clone(), slot.clone()))
+            })
+            .collect()
+    }
+
+    // Calls a function with a slot if its value is still alive,
+    // holding the lock on the stacks like `live_slot`.
+    fn if_alive<F, R>(&self, slot: &Slot, f: F) -> Option<R>
+        where F: FnOnce(&Slot) -> R
+    {
+        let _stacks = self.stacks();
+        if slot.is_alive() { Some(f(slot)) } else { None }
+    }
+
+    // Calls a function with the current slot.
+    fn try_slot<T, Tag, F, R>(&self, slot_key: Option<SlotKey>, f: F)
+        -> Result<R, NoCurrentError>
+        where T: ?Sized + Any, Tag: Any, F: FnOnce(&Slot) -> R
+    {
+        let key = Key::of::<T, Tag>(slot_key);
+        self.live_slot(&key, f).map_err(|removed| {
+            NoCurrentError::new(Name::of::<T, Tag>(key.slot_key), removed)
+        })
+    }
+
+    // Copies the stack of current slots, leaving out those reinstalled
+    // from a `Context` whose value is gone.
+    fn stack<T: ?Sized + Any, Tag: Any>(&self, slot_key: Option<SlotKey>)
+        -> Vec<Slot>
+    {
+        let key = Key::of::<T, Tag>(slot_key);
+        self.stacks().get(&key).map_or(vec![], |stack| {
+            stack.iter().filter(|slot| slot.is_alive()).cloned().collect()
+        })
+    }
+}
+
+/// Where current values are stored, chosen with the `S` parameter
+/// of `Current` and of the guards, such as `CurrentGuard` and `CurrentBox`.
+///
+/// Values are stored in a `ContextMap`: one for each thread
+/// with `ThreadLocal`, one for each task with `TaskLocal`,
+/// one for the process with `Global`, or a map passed explicitly,
+/// since a `ContextMap` is a storage itself.
+/// Implement this to keep the values of a subsystem in a map of its own,
+/// together with `Stores` for the types it keeps.
+///
+/// Methods without a storage argument, such as `Current::with`,
+/// use the `Default` value of the storage.
+///
+/// ```
+/// extern crate current;
+///
+/// use current::{ ContextMap, Current, CurrentGuard, CurrentStorage, Stores };
+///
+/// thread_local!(static PLUGINS: ContextMap = ContextMap::new());
+///
+/// #[derive(Default)]
+/// struct Plugins;
+///
+/// impl CurrentStorage for Plugins {
+///     fn with_map<F, R>(&self, f: F) -> Option<R>
+///         where F: FnOnce(&ContextMap) -> R
+///     {
+///         Some(PLUGINS.with(f))
+///     }
+/// }
+///
+/// impl<T> Stores<T> for Plugins where T: ?Sized {}
+///
+/// fn main() {
+///     let mut name = "audio";
+///     CurrentGuard::scope_in(&Plugins, &mut name, || {
+///         assert_eq!(Current::<&str, (), Plugins>::with(|name| *name),
+///                    Some("audio"));
+///         assert_eq!(Current::<&str>::with(|name| *name), None);
+///     });
+/// }
+/// ```
+pub trait CurrentStorage: Any {
+    /// Calls a closure with the map to set and look up current values in,
+    /// or returns `None` if there is none at this point.
+    fn with_map<F, R>(&self, f: F) -> Option<R>
+        where F: FnOnce(&ContextMap) -> R;
+}
+
+/// A storage that can keep current values of type `T`.
+///
+/// Storages that keep values on one thread can keep values of any type,
+/// while `Global` only keeps values that are `Send` and `Sync`.
+pub trait Stores<T: ?Sized>: CurrentStorage {}
+
+/// Stores current values for each thread, which is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadLocal;
+
+impl CurrentStorage for ThreadLocal {
+    fn with_map<F, R>(&self, f: F) -> Option<R>
+        where F: FnOnce(&ContextMap) -> R
+    {
+        Some(f(&ContextMap::local()))
+    }
+}
+
+impl<T> Stores<T> for ThreadLocal where T: ?Sized {}
+
+impl CurrentStorage for ContextMap {
+    fn with_map<F, R>(&self, f: F) -> Option<R>
+        where F: FnOnce(&ContextMap) -> R
+    {
+        Some(f(self))
+    }
+}
+
+impl<T> Stores<T> for ContextMap where T: ?Sized {}
+
+// Returns the map of a storage to set a value in.
+// Only `Global` sets values in the process-global map,
+// since other storages take values that are not `Send` and `Sync`.
+#[track_caller]
+fn storage_map<S: CurrentStorage>(storage: &S) -> ContextMap {
+    match storage.with_map(ContextMap::share) {
+        Some(ref map)
+            if map.shared && TypeId::of::<S>() != TypeId::of::<Global>() =>
+        {
+            panic!("Values are set in the process-global map with `Global`, \
+                    not with storage `{}`", type_name::<S>())
+        }
+        Some(map) => map,
+        None => panic!("No map to set current values in for storage `{}`",
+                       type_name::<S>())
+    }
+}
+
+/// Puts back the previous current pointer in a `ContextMap`.
+pub struct MapGuard<'a, T> where T: ?Sized + Any {
+    _installed: Installed,
+    _val: PhantomData<&'a mut T>
+}
+
+// A pointer to push as a current value,
+// with the key and name of the stack to push it on.
+// Views come with a function to update their pointer.
+struct Pending {
+    key: Key,
+    name: Arc<Name>,
+    ptr: Arc<dyn Any + Send + Sync>,
+    update: Option<Box<dyn Fn() + Send + Sync>>
+}
+
+impl Pending {
+    fn new<T: ?Sized + Any, Tag: Any>(
+        ptr: *mut T,
+        slot_key: Option<SlotKey>
+    ) -> Pending {
+        Pending {
+            key: Key::of::<T, Tag>(slot_key.clone()),
+            name: Arc::new(Name::of::<T, Tag>(slot_key)),
+            ptr: Arc::new(SlotPtr::new(ptr)),
+            update: None
+        }
+    }
+
+    // Creates the pointer for a view of a value,
+    // which is computed again from the value when updated.
+    fn view<T: ?Sized + Any, U: ?Sized + Any>(
+        ptr: *mut T,
+        f: fn(&mut T) -> &mut U
+    ) -> Pending {
+        let view = Arc::new(SlotPtr::new(f(unsafe { &mut *ptr }) as *mut U));
+        let update = {
+            let view = view.clone();
+            let val = SlotPtr::new(ptr);
+            move || view.set(f(unsafe { &mut *val.get() }) as *mut U)
+        };
+        Pending {
+            key: Key::of::<U, ()>(None),
+            name: Arc::new(Name::of::<U, ()>(None)),
+            ptr: view,
+            update: Some(Box::new(update))
+        }
+    }
+
+    // Pushes the pointer, sharing the borrow state with other pointers
+    // to the same value.
+    #[track_caller]
+    fn install(self, map: &ContextMap, read_only: bool, borrow: BorrowFlag)
+        -> Installed
+    {
+        let guard = next_guard();
+        let site = Location::caller();
+        if let Some(update) = self.update {
+            lock(&borrow.views).push(update);
+        }
+        let alive = Arc::new(AtomicBool::new(true));
+        map.push(self.key.clone(), Slot {
+            ptr: self.ptr,
+            name: self.name.clone(),
+            read_only,
+            borrow: borrow.clone(),
+            alive: alive.clone(),
+            guard,
+            site
+        });
+        Installed {
+            map: map.share(),
+            key: self.key,
+            name: self.name,
+            borrow,
+            alive,
+            guard,
+            site
+        }
+    }
+}
+
+// The slot pushed for a current value,
+// which is removed again when this is dropped.
+struct Installed {
+    map: ContextMap,
+    key: Key,
+    name: Arc<Name>,
+    borrow: BorrowFlag,
+    alive: Arc<AtomicBool>,
+    guard: u64,
+    site: &'static Location<'static>
+}
+
+impl Installed {
+    // Pushes the pointer on the stacks of a map.
+    #[track_caller]
+    fn new_in<T: ?Sized + Any, Tag: Any>(
+        map: &ContextMap,
+        ptr: *mut T,
+        read_only: bool,
+        slot_key: Option<SlotKey>
+    ) -> Installed {
+        let borrow = BorrowState::new();
+        Pending::new::<T, Tag>(ptr, slot_key).install(map, read_only, borrow)
+    }
+
+    // Returns whether the value is borrowed through `Current`
+    // on this thread, which panics when dropping it.
+    // Borrows in the process-global map are waited for instead.
+    fn is_borrowed(&self) -> bool {
+        !self.map.shared && self.borrow.get() != UNUSED
+    }
+}
+
+impl Drop for Installed {
+    fn drop(&mut self) {
+        // Views must not be updated from the value once it is gone.
+        lock(&self.borrow.views).clear();
+        self.map.retire(&self.alive);
+        if let Some(top) = self.map.remove(&self.key, self.guard) {
+            let msg = format!("Current guard for {} created at {} was \
+                dropped before the guard created at {}",
+                self.name, self.site, top);
+            match OutOfOrder::get() {
+                OutOfOrder::Panic if !thread::panicking() => panic!("{}", msg),
+                OutOfOrder::Panic | OutOfOrder::Log => eprintln!("{}", msg),
+                OutOfOrder::Repair => {}
             }
+        }
+        if self.map.shared {
+            // Borrows on other threads may have started before the value
+            // was retired.
+            self.borrow.wait_unused();
+        } else if self.is_borrowed() && !thread::panicking() {
+            panic!("Current {} was dropped while borrowed", self.name);
+        }
+    }
+}
+
+/// Puts back the previous current pointer.
+///
+/// The `Tag` type tells apart independent current values of the same type,
+/// which are accessed with `Current<T, Tag>`.
+/// The `S` type chooses where the value is stored, see `CurrentStorage`.
+pub struct CurrentGuard<'a, T, Tag = (), S = ThreadLocal>
+    where T: ?Sized + Any, Tag: Any, S: CurrentStorage
+{
+    ptr: *mut T,
+    // Declared before `installed`, so views are removed first.
+    _views: Vec<Installed>,
+    installed: Installed,
+    _val: PhantomData<&'a mut T>,
+    _tag: PhantomData<Tag>,
+    _storage: PhantomData<S>
+}
+
+impl<'a, T> CurrentGuard<'a, T> where T: ?Sized + Any {
+    /// Makes a value current while calling a closure.
+    ///
+    /// This is the safe way to set a current value,
+    /// since the value is guaranteed to be removed again
+    /// before the borrow of it ends.
+    #[track_caller]
+    pub fn scope<F, R>(val: &mut T, f: F) -> R where F: FnOnce() -> R {
+        CurrentGuard::<T>::scope_tagged(val, f)
+    }
+
+    /// Creates a new current guard.
+    ///
+    /// Guards for the same type should be dropped in the reverse order
+    /// of creation, see `OutOfOrder` for what happens otherwise.
+    ///
+    /// # Safety
+    ///
+    /// The guard must be dropped before the borrow of the value ends.
+    /// Leaking it, for example with `mem::forget`, leaves a dangling
+    /// pointer to the value in the current values,
+    /// which safe code can then access through `Current::with`.
+    #[track_caller]
+    pub unsafe fn new(val: &mut T) -> CurrentGuard<'_, T> {
+        CurrentGuard::new_tagged(val)
+    }
+
+    /// Starts building a guard that makes a value current
+    /// under its own type and under views of it, such as trait objects.
+    #[track_caller]
+    pub fn builder(val: &mut T) -> CurrentBuilder<'_, T> {
+        CurrentBuilder::new(val)
+    }
+
+    /// Makes a value current under a runtime key while calling a closure.
+    ///
+    /// The value is accessed with `Current::with_named`,
+    /// and shadows only values set under the same key.
+    #[track_caller]
+    pub fn scope_named<K, F, R>(key: K, val: &mut T, f: F) -> R
+        where K: Into<SlotKey>, F: FnOnce() -> R
+    {
+        CurrentGuard::<T>::scope_named_tagged(key, val, f)
+    }
+
+    /// Creates a new current guard for a runtime key.
+    ///
+    /// # Safety
+    ///
+    /// See `CurrentGuard::new`.
+    #[track_caller]
+    pub unsafe fn new_named<K>(key: K, val: &mut T) -> CurrentGuard<'_, T>
+        where K: Into<SlotKey>
+    {
+        CurrentGuard::new_named_tagged(key, val)
+    }
+}
+
+impl<'a, T, S> CurrentGuard<'a, T, (), S> where T: ?Sized + Any, S: Stores<T> {
+    /// Makes a value current in a storage while calling a closure,
+    /// such as in a `ContextMap`.
+    ///
+    /// See `CurrentGuard::scope`.
+    #[track_caller]
+    pub fn scope_in<F, R>(storage: &S, val: &mut T, f: F) -> R
+        where F: FnOnce() -> R
+    {
+        CurrentGuard::<T, (), S>::scope_tagged_in(storage, val, f)
+    }
+
+    /// Creates a new current guard for a storage.
+    ///
+    /// # Safety
+    ///
+    /// See `CurrentGuard::new`.
+    #[track_caller]
+    pub unsafe fn new_in<'b>(storage: &S, val: &'b mut T)
+        -> CurrentGuard<'b, T, (), S>
+    {
+        CurrentGuard::new_tagged_in(storage, val)
+    }
+}
+
+#[allow(trivial_casts)]
+impl<'a, T, Tag, S> CurrentGuard<'a, T, Tag, S>
+    where T: ?Sized + Any, Tag: Any, S: Stores<T>
+{
+    /// Makes a value current under a tag while calling a closure.
+    ///
+    /// See `CurrentGuard::scope`.
+    #[track_caller]
+    pub fn scope_tagged<F, R>(val: &mut T, f: F) -> R
+        where S: Default, F: FnOnce() -> R
+    {
+        CurrentGuard::<T, Tag, S>::scope_tagged_in(&S::default(), val, f)
+    }
+
+    /// Creates a new current guard for a tag.
+    ///
+    /// # Safety
+    ///
+    /// See `CurrentGuard::new`.
+    #[track_caller]
+    pub unsafe fn new_tagged(val: &mut T) -> CurrentGuard<'_, T, Tag, S>
+        where S: Default
+    {
+        CurrentGuard::new_tagged_in(&S::default(), val)
+    }
+
+    /// Makes a value current under a tag in a storage
+    /// while calling a closure.
+    ///
+    /// See `CurrentGuard::scope`.
+    #[track_caller]
+    pub fn scope_tagged_in<F, R>(storage: &S, val: &mut T, f: F) -> R
+        where F: FnOnce() -> R
+    {
+        let _guard = unsafe {
+            CurrentGuard::<T, Tag, S>::new_tagged_in(storage, val)
         };
+        f()
+    }
+
+    /// Creates a new current guard for a tag and a storage.
+    ///
+    /// # Safety
+    ///
+    /// See `CurrentGuard::new`.
+    #[track_caller]
+    pub unsafe fn new_tagged_in<'b>(storage: &S, val: &'b mut T)
+        -> CurrentGuard<'b, T, Tag, S>
+    {
+        CurrentGuard::install(storage, val, None)
+    }
+
+    /// Makes a value current under a tag and a runtime key
+    /// while calling a closure.
+    ///
+    /// The value is accessed with `Current::<T, Tag>::with_named`.
+    #[track_caller]
+    pub fn scope_named_tagged<K, F, R>(key: K, val: &mut T, f: F) -> R
+        where S: Default, K: Into<SlotKey>, F: FnOnce() -> R
+    {
+        let _guard = unsafe {
+            CurrentGuard::<T, Tag, S>::new_named_tagged(key, val)
+        };
+        f()
+    }
+
+    /// Creates a new current guard for a tag and a runtime key.
+    ///
+    /// # Safety
+    ///
+    /// See `CurrentGuard::new`.
+    #[track_caller]
+    pub unsafe fn new_named_tagged<K>(key: K, val: &mut T)
+        -> CurrentGuard<'_, T, Tag, S>
+        where S: Default, K: Into<SlotKey>
+    {
+        CurrentGuard::install(&S::default(), val, Some(key.into()))
+    }
+
+    #[track_caller]
+    unsafe fn install<'b>(
+        storage: &S,
+        val: &'b mut T,
+        slot_key: Option<SlotKey>
+    ) -> CurrentGuard<'b, T, Tag, S> {
+        let ptr = val as *mut T;
+        let map = storage_map(storage);
+        CurrentGuard {
+            ptr,
+            _views: vec![],
+            installed: Installed::new_in::<T, Tag>(&map, ptr, false, slot_key),
+            _val: PhantomData,
+            _tag: PhantomData,
+            _storage: PhantomData
+        }
+    }
+}
+
+impl<'a, T, Tag, S> CurrentGuard<'a, T, Tag, S>
+    where T: ?Sized + Any, Tag: Any, S: CurrentStorage
+{
+    /// Immutably borrows the value set by this guard.
+    ///
+    /// Panics if the value is mutably borrowed.
+    ///
+    /// The guard does not implement `Deref`, since a plain reference
+    /// would not be tracked and could overlap with `Current::with_mut`.
+    pub fn get(&self) -> Ref<'_, T> {
+        let installed = &self.installed;
+        Ref::acquire(self.ptr, installed.borrow.clone(), &installed.name)
+    }
+
+    /// Mutably borrows the value set by this guard.
+    ///
+    /// Panics if the value is already borrowed.
+    /// See `get` for why the guard does not implement `DerefMut`.
+    pub fn get_mut(&mut self) -> RefMut<'_, T> {
+        let installed = &self.installed;
+        RefMut::acquire(self.ptr, installed.borrow.clone(), &installed.name)
+    }
+}
+
+/// Builds a `CurrentGuard` that also makes views of the value current,
+/// for example as `Current<dyn Trait>`.
+///
+/// All views share the borrow state of the value,
+/// and are removed together with it when the guard is dropped.
+pub struct CurrentBuilder<'a, T, S = ThreadLocal>
+    where T: ?Sized + Any, S: CurrentStorage
+{
+    ptr: *mut T,
+    map: ContextMap,
+    views: Vec<Pending>,
+    _val: PhantomData<&'a mut T>,
+    _storage: PhantomData<S>
+}
+
+impl<'a, T, S> CurrentBuilder<'a, T, S>
+    where T: ?Sized + Any, S: Stores<T>
+{
+    /// Starts building a guard for a storage.
+    ///
+    /// See `CurrentGuard::builder`.
+    #[track_caller]
+    pub fn new(val: &'a mut T) -> CurrentBuilder<'a, T, S> where S: Default {
+        CurrentBuilder::new_in(&S::default(), val)
+    }
+
+    /// Starts building a guard for a storage instance,
+    /// such as a `ContextMap`.
+    ///
+    /// See `CurrentGuard::builder`.
+    #[track_caller]
+    pub fn new_in(storage: &S, val: &'a mut T) -> CurrentBuilder<'a, T, S> {
+        CurrentBuilder {
+            ptr: val as *mut T,
+            map: storage_map(storage),
+            views: vec![],
+            _val: PhantomData,
+            _storage: PhantomData
+        }
+    }
+
+    /// Adds a view of the value, such as a trait object.
+    ///
+    /// The view is made current as `Current<U, (), S>`.
+    /// It is computed again with `f` whenever a mutable borrow
+    /// of the value or of one of its views ends,
+    /// so views of data the value owns, such as the elements of a `Vec`,
+    /// follow the value when it changes.
+    pub fn view<U>(mut self, f: fn(&mut T) -> &mut U)
+        -> CurrentBuilder<'a, T, S>
+        where U: ?Sized + Any, S: Stores<U>
+    {
+        self.views.push(Pending::view(self.ptr, f));
+        self
+    }
+
+    /// Makes the value and its views current while calling a closure.
+    ///
+    /// See `CurrentGuard::scope`.
+    #[track_caller]
+    pub fn scope<F, R>(self, f: F) -> R where F: FnOnce() -> R {
+        let _guard = unsafe { self.build() };
+        f()
+    }
+
+    /// Creates the guard.
+    ///
+    /// # Safety
+    ///
+    /// See `CurrentGuard::new`.
+    #[track_caller]
+    pub unsafe fn build(self) -> CurrentGuard<'a, T, (), S> {
+        let installed = Installed::new_in::<T, ()>(&self.map, self.ptr, false,
+                                                   None);
+        let mut views = Vec::with_capacity(self.views.len());
+        for view in self.views {
+            let borrow = installed.borrow.clone();
+            views.push(view.install(&self.map, false, borrow));
+        }
+        // Views are dropped in order, so reverse them to remove
+        // views of the same type in the opposite order of installing.
+        views.reverse();
+        CurrentGuard {
+            ptr: self.ptr,
+            _views: views,
+            installed,
+            _val: PhantomData,
+            _tag: PhantomData,
+            _storage: PhantomData
+        }
+    }
+}
+
+/// Puts back the previous current pointer
+/// after making a shared reference current.
+///
+/// The value can only be borrowed immutably while it is current,
+/// mutable access through `Current` panics.
+pub struct SharedGuard<'a, T, Tag = (), S = ThreadLocal>
+    where T: ?Sized + Any, Tag: Any, S: CurrentStorage
+{
+    _val: &'a T,
+    _installed: Installed,
+    _tag: PhantomData<Tag>,
+    _storage: PhantomData<S>
+}
+
+impl<'a, T> SharedGuard<'a, T> where T: ?Sized + Any {
+    /// Makes a shared reference current while calling a closure.
+    ///
+    /// See `CurrentGuard::scope`.
+    #[track_caller]
+    pub fn scope<F, R>(val: &T, f: F) -> R where F: FnOnce() -> R {
+        SharedGuard::<T>::scope_tagged(val, f)
+    }
+
+    /// Creates a new shared guard.
+    ///
+    /// # Safety
+    ///
+    /// See `CurrentGuard::new`.
+    #[track_caller]
+    pub unsafe fn new(val: &T) -> SharedGuard<'_, T> {
+        SharedGuard::new_tagged(val)
+    }
+}
+
+impl<'a, T, S> SharedGuard<'a, T, (), S> where T: ?Sized + Any, S: Stores<T> {
+    /// Makes a shared reference current in a storage
+    /// while calling a closure.
+    ///
+    /// See `CurrentGuard::scope`.
+    #[track_caller]
+    pub fn scope_in<F, R>(storage: &S, val: &T, f: F) -> R
+        where F: FnOnce() -> R
+    {
+        SharedGuard::<T, (), S>::scope_tagged_in(storage, val, f)
+    }
+
+    /// Creates a new shared guard for a storage.
+    ///
+    /// # Safety
+    ///
+    /// See `CurrentGuard::new`.
+    #[track_caller]
+    pub unsafe fn new_in<'b>(storage: &S, val: &'b T)
+        -> SharedGuard<'b, T, (), S>
+    {
+        SharedGuard::new_tagged_in(storage, val)
+    }
+}
+
+#[allow(trivial_casts)]
+impl<'a, T, Tag, S> SharedGuard<'a, T, Tag, S>
+    where T: ?Sized + Any, Tag: Any, S: Stores<T>
+{
+    /// Makes a shared reference current under a tag
+    /// while calling a closure.
+    ///
+    /// See `CurrentGuard::scope`.
+    #[track_caller]
+    pub fn scope_tagged<F, R>(val: &T, f: F) -> R
+        where S: Default, F: FnOnce() -> R
+    {
+        SharedGuard::<T, Tag, S>::scope_tagged_in(&S::default(), val, f)
+    }
+
+    /// Creates a new shared guard for a tag.
+    ///
+    /// # Safety
+    ///
+    /// See `CurrentGuard::new`.
+    #[track_caller]
+    pub unsafe fn new_tagged(val: &T) -> SharedGuard<'_, T, Tag, S>
+        where S: Default
+    {
+        SharedGuard::new_tagged_in(&S::default(), val)
+    }
+
+    /// Makes a shared reference current under a tag in a storage
+    /// while calling a closure.
+    ///
+    /// See `CurrentGuard::scope`.
+    #[track_caller]
+    pub fn scope_tagged_in<F, R>(storage: &S, val: &T, f: F) -> R
+        where F: FnOnce() -> R
+    {
+        let _guard = unsafe {
+            SharedGuard::<T, Tag, S>::new_tagged_in(storage, val)
+        };
+        f()
+    }
+
+    /// Creates a new shared guard for a tag and a storage.
+    ///
+    /// # Safety
+    ///
+    /// See `CurrentGuard::new`.
+    #[track_caller]
+    pub unsafe fn new_tagged_in<'b>(storage: &S, val: &'b T)
+        -> SharedGuard<'b, T, Tag, S>
+    {
+        let ptr = val as *const T as *mut T;
+        let map = storage_map(storage);
+        SharedGuard {
+            _val: val,
+            _installed: Installed::new_in::<T, Tag>(&map, ptr, true, None),
+            _tag: PhantomData,
+            _storage: PhantomData
+        }
+    }
+}
+
+/// Owns a value and keeps it current until dropped,
+/// putting back the previous current pointer
+/// the same way as `CurrentGuard`.
+///
+/// Since the value is kept on the heap, leaking the box is safe.
+pub struct CurrentBox<T, Tag = (), S = ThreadLocal>
+    where T: ?Sized + Any, Tag: Any, S: CurrentStorage
+{
+    installed: Option<Installed>,
+    ptr: *mut T,
+    _tag: PhantomData<Tag>,
+    _storage: PhantomData<S>
+}
+
+impl<T> CurrentBox<T> where T: ?Sized + Any {
+    /// Moves a value to the heap and makes it current.
+    #[track_caller]
+    pub fn new(val: T) -> CurrentBox<T> where T: Sized {
+        CurrentBox::new_tagged(val)
+    }
+
+    /// Makes a boxed value current.
+    #[track_caller]
+    pub fn from_box(val: Box<T>) -> CurrentBox<T> {
+        CurrentBox::from_box_tagged(val)
+    }
+}
+
+impl<T, S> CurrentBox<T, (), S> where T: ?Sized + Any, S: Stores<T> {
+    /// Moves a value to the heap and makes it current in a storage.
+    #[track_caller]
+    pub fn new_in(storage: &S, val: T) -> CurrentBox<T, (), S> where T: Sized {
+        CurrentBox::from_box_tagged_in(storage, Box::new(val))
+    }
+
+    /// Makes a boxed value current in a storage.
+    #[track_caller]
+    pub fn from_box_in(storage: &S, val: Box<T>) -> CurrentBox<T, (), S> {
+        CurrentBox::from_box_tagged_in(storage, val)
+    }
+}
+
+impl<T, Tag, S> CurrentBox<T, Tag, S>
+    where T: ?Sized + Any, Tag: Any, S: Stores<T>
+{
+    /// Moves a value to the heap and makes it current under a tag.
+    #[track_caller]
+    pub fn new_tagged(val: T) -> CurrentBox<T, Tag, S>
+        where T: Sized, S: Default
+    {
+        CurrentBox::from_box_tagged(Box::new(val))
+    }
+
+    /// Makes a boxed value current under a tag.
+    #[track_caller]
+    pub fn from_box_tagged(val: Box<T>) -> CurrentBox<T, Tag, S>
+        where S: Default
+    {
+        CurrentBox::from_box_tagged_in(&S::default(), val)
+    }
+
+    /// Makes a boxed value current under a tag in a storage.
+    #[track_caller]
+    pub fn from_box_tagged_in(storage: &S, val: Box<T>)
+        -> CurrentBox<T, Tag, S>
+    {
+        let map = storage_map(storage);
+        let ptr = Box::into_raw(val);
+        let installed = Installed::new_in::<T, Tag>(&map, ptr, false, None);
+        CurrentBox {
+            installed: Some(installed),
+            ptr,
+            _tag: PhantomData,
+            _storage: PhantomData
+        }
+    }
+}
+
+impl<T, Tag, S> CurrentBox<T, Tag, S>
+    where T: ?Sized + Any, Tag: Any, S: CurrentStorage
+{
+    /// Removes the value from the current values and returns it.
+    pub fn into_inner(self) -> T where T: Sized {
+        *self.into_box()
+    }
+
+    /// Removes the value from the current values and returns it boxed.
+    ///
+    /// Panics if the value is borrowed, leaking it.
+    /// Values in the process-global map wait for borrows
+    /// on other threads to end instead.
+    pub fn into_box(mut self) -> Box<T> {
+        if self.is_borrowed() {
+            let name = &self.installed.as_ref().unwrap().name;
+            panic!("Current {} was dropped while borrowed", name);
+        }
+        drop(self.installed.take());
+        let val = unsafe { Box::from_raw(self.ptr) };
+        mem::forget(self);
+        val
+    }
+
+    // Returns whether the value is borrowed through `Current`.
+    fn is_borrowed(&self) -> bool {
+        self.installed.as_ref().is_some_and(Installed::is_borrowed)
+    }
+}
+
+impl<T, Tag, S> Drop for CurrentBox<T, Tag, S>
+    where T: ?Sized + Any, Tag: Any, S: CurrentStorage
+{
+    fn drop(&mut self) {
+        // A value that is still borrowed is leaked instead of freed,
+        // since dropping it while unwinding does not panic.
+        let borrowed = self.is_borrowed();
+        drop(self.installed.take());
+        if !borrowed {
+            drop(unsafe { Box::from_raw(self.ptr) });
+        }
     }
 }
 
 /// The current value of a type.
-pub struct Current<T>(PhantomData<T>);
+///
+/// Values set under a tag with `CurrentGuard<T, Tag>`
+/// are accessed with `Current<T, Tag>`,
+/// and values in another storage with `Current<T, Tag, S>`.
+///
+/// `Current` does not implement `Deref` and `DerefMut`,
+/// since two handles could then hand out aliasing mutable references.
+/// Use `borrow` and `borrow_mut`, which track their borrows.
+pub struct Current<T, Tag = (), S = ThreadLocal>
+    where T: ?Sized + Any, Tag: Any, S: CurrentStorage
+{
+    map: Option<ContextMap>,
+    slot_key: Option<SlotKey>,
+    _marker: PhantomData<T>,
+    _tag: PhantomData<Tag>,
+    _storage: PhantomData<S>
+}
 
-impl<T> Current<T> where T: Any {
+impl<T, Tag, S> Current<T, Tag, S>
+    where T: ?Sized + Any, Tag: Any, S: CurrentStorage
+{
     /// Creates a new current object
-    pub unsafe fn new() -> Current<T> { Current(PhantomData) }
+    ///
+    /// # Safety
+    ///
+    /// The references handed out by `borrow`, `borrow_mut`, `try_get`,
+    /// `try_get_mut`, `iter`, `parent`, `ancestors`, `current`
+    /// and `current_unwrap` are not tied to the lifetime
+    /// of the `CurrentGuard` that set the value.
+    /// The caller must not keep them after the guard is dropped.
+    /// Only `current` and `current_unwrap` hand out plain references,
+    /// which are not tracked and must not overlap with other borrows.
+    pub unsafe fn new() -> Current<T, Tag, S> where S: Default {
+        Current::new_in(&S::default())
+    }
+
+    /// Creates a new current object for the values of a storage,
+    /// such as a `ContextMap`.
+    ///
+    /// The map of the storage is looked up once, when this is called.
+    ///
+    /// # Safety
+    ///
+    /// See `Current::new`.
+    pub unsafe fn new_in(storage: &S) -> Current<T, Tag, S> {
+        Current {
+            map: storage.with_map(ContextMap::share),
+            slot_key: None,
+            _marker: PhantomData,
+            _tag: PhantomData,
+            _storage: PhantomData
+        }
+    }
+
+    /// Creates a new current object for the value
+    /// set under a runtime key with `CurrentGuard::new_named`.
+    ///
+    /// # Safety
+    ///
+    /// See `Current::new`.
+    pub unsafe fn named<K>(key: K) -> Current<T, Tag, S>
+        where S: Default, K: Into<SlotKey>
+    {
+        Current { slot_key: Some(key.into()), ..Current::new() }
+    }
+
+    // Returns the map of the default storage.
+    fn default_map() -> Option<ContextMap> where S: Default {
+        S::default().with_map(ContextMap::share)
+    }
+
+    // Calls a function with the current slot of a map.
+    fn lookup<F, R>(
+        map: &Option<ContextMap>,
+        slot_key: Option<SlotKey>,
+        f: F
+    ) -> Result<R, NoCurrentError>
+        where F: FnOnce(&Slot) -> R
+    {
+        match *map {
+            Some(ref map) => map.try_slot::<T, Tag, F, R>(slot_key, f),
+            None => {
+                let name = Name::of::<T, Tag>(slot_key);
+                Err(NoCurrentError::new(name, false))
+            }
+        }
+    }
+
+    // Looks up the current slot of the default storage.
+    fn try_slot(slot_key: Option<SlotKey>) -> Result<Slot, NoCurrentError>
+        where S: Default
+    {
+        Self::lookup(&Self::default_map(), slot_key, Slot::clone)
+    }
+
+    // Borrows the current object of the default storage.
+    fn try_ref<'b>(slot_key: Option<SlotKey>)
+        -> Result<Ref<'b, T>, NoCurrentError>
+        where S: Default
+    {
+        Self::lookup(&Self::default_map(), slot_key, Ref::new)
+    }
+
+    // Mutably borrows the current object of the default storage.
+    fn try_ref_mut<'b>(slot_key: Option<SlotKey>)
+        -> Result<RefMut<'b, T>, NoCurrentError>
+        where S: Default
+    {
+        Self::lookup(&Self::default_map(), slot_key, RefMut::new)
+    }
+
+    // Copies the stack of current slots of a map.
+    fn stack(map: &Option<ContextMap>, slot_key: Option<SlotKey>)
+        -> Vec<Slot>
+    {
+        map.as_ref().map_or(vec![], |map| map.stack::<T, Tag>(slot_key))
+    }
+
+    // Calls a function with the current slot of this object,
+    // panicking with a message naming the type if it is not set.
+    fn lookup_unwrap<F, R>(&self, f: F) -> R where F: FnOnce(&Slot) -> R {
+        match Self::lookup(&self.map, self.slot_key.clone(), f) {
+            Err(err) => panic!("{}", err),
+            Ok(val) => val
+        }
+    }
+
+    /// Calls a closure with a shared reference to the current object.
+    ///
+    /// Returns `None` if no current object of type `T` is set.
+    /// Panics if the current object is mutably borrowed.
+    pub fn with<F, R>(f: F) -> Option<R>
+        where S: Default, F: FnOnce(&T) -> R
+    {
+        let val = Self::try_ref(None).ok()?;
+        Some(f(&val))
+    }
+
+    /// Calls a closure with a mutable reference to the current object.
+    ///
+    /// Returns `None` if no current object of type `T` is set.
+    /// Panics if the current object is already borrowed
+    /// or was set from a shared reference.
+    pub fn with_mut<F, R>(f: F) -> Option<R>
+        where S: Default, F: FnOnce(&mut T) -> R
+    {
+        let mut val = Self::try_ref_mut(None).ok()?;
+        Some(f(&mut val))
+    }
+
+    /// Calls a closure with a shared reference to the current object.
+    ///
+    /// Returns an error if no current object of type `T` is set.
+    /// Panics if the current object is mutably borrowed.
+    pub fn try_with<F, R>(f: F) -> Result<R, NoCurrentError>
+        where S: Default, F: FnOnce(&T) -> R
+    {
+        let val = Self::try_ref(None)?;
+        Ok(f(&val))
+    }
+
+    /// Calls a closure with a mutable reference to the current object.
+    ///
+    /// Returns an error if no current object of type `T` is set.
+    /// Panics if the current object is already borrowed
+    /// or was set from a shared reference.
+    pub fn try_with_mut<F, R>(f: F) -> Result<R, NoCurrentError>
+        where S: Default, F: FnOnce(&mut T) -> R
+    {
+        let mut val = Self::try_ref_mut(None)?;
+        Ok(f(&mut val))
+    }
+
+    /// Calls a closure with a shared reference
+    /// to the current object set under a runtime key.
+    ///
+    /// See `Current::with`.
+    pub fn with_named<K, F, R>(key: K, f: F) -> Option<R>
+        where S: Default, K: Into<SlotKey>, F: FnOnce(&T) -> R
+    {
+        Self::try_with_named(key, f).ok()
+    }
+
+    /// Calls a closure with a mutable reference
+    /// to the current object set under a runtime key.
+    ///
+    /// See `Current::with_mut`.
+    pub fn with_named_mut<K, F, R>(key: K, f: F) -> Option<R>
+        where S: Default, K: Into<SlotKey>, F: FnOnce(&mut T) -> R
+    {
+        Self::try_with_named_mut(key, f).ok()
+    }
+
+    /// Calls a closure with a shared reference
+    /// to the current object set under a runtime key.
+    ///
+    /// See `Current::try_with`.
+    pub fn try_with_named<K, F, R>(key: K, f: F) -> Result<R, NoCurrentError>
+        where S: Default, K: Into<SlotKey>, F: FnOnce(&T) -> R
+    {
+        let val = Self::try_ref(Some(key.into()))?;
+        Ok(f(&val))
+    }
+
+    /// Calls a closure with a mutable reference
+    /// to the current object set under a runtime key.
+    ///
+    /// See `Current::try_with_mut`.
+    pub fn try_with_named_mut<K, F, R>(key: K, f: F)
+        -> Result<R, NoCurrentError>
+        where S: Default, K: Into<SlotKey>, F: FnOnce(&mut T) -> R
+    {
+        let mut val = Self::try_ref_mut(Some(key.into()))?;
+        Ok(f(&mut val))
+    }
+
+    /// Returns the number of objects of type `T` set,
+    /// counting the current one and the ones it shadows.
+    pub fn depth() -> usize where S: Default {
+        Self::stack(&Self::default_map(), None).len()
+    }
+
+    /// Calls a closure with an iterator over the current object
+    /// and the objects it shadows, from the innermost to the outermost.
+    ///
+    /// Each object is borrowed immutably when the iterator reaches it,
+    /// which panics if it is mutably borrowed.
+    pub fn with_stack<F, R>(f: F) -> R
+        where S: Default, F: FnOnce(Iter<'_, T>) -> R
+    {
+        let map = Self::default_map();
+        f(Iter::new(&map, Self::stack(&map, None)))
+    }
+
+    /// Calls a closure with a shared reference to the object
+    /// shadowed by the current object.
+    ///
+    /// Returns `None` if fewer than two objects of type `T` are set.
+    /// Panics if the shadowed object is mutably borrowed.
+    pub fn with_parent<F, R>(f: F) -> Option<R>
+        where S: Default, F: FnOnce(&T) -> R
+    {
+        let val = Self::ancestors_of(&Self::default_map(), None).next()?;
+        Some(f(&val))
+    }
+
+    /// Calls a closure with an iterator over the objects
+    /// shadowed by the current object, from the innermost to the outermost.
+    ///
+    /// See `Current::with_stack`.
+    pub fn with_ancestors<F, R>(f: F) -> R
+        where S: Default, F: FnOnce(Iter<'_, T>) -> R
+    {
+        f(Self::ancestors_of(&Self::default_map(), None))
+    }
+
+    /// Iterates over the current object and the objects it shadows,
+    /// from the innermost to the outermost.
+    ///
+    /// See `Current::with_stack`.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter::new(&self.map, Self::stack(&self.map, self.slot_key.clone()))
+    }
+
+    /// Immutably borrows the object shadowed by the current object.
+    ///
+    /// See `Current::with_parent`.
+    pub fn parent(&self) -> Option<Ref<'_, T>> {
+        Self::ancestors_of(&self.map, self.slot_key.clone()).next()
+    }
+
+    /// Iterates over the objects shadowed by the current object,
+    /// from the innermost to the outermost.
+    ///
+    /// See `Current::with_stack`.
+    pub fn ancestors(&self) -> Iter<'_, T> {
+        Self::ancestors_of(&self.map, self.slot_key.clone())
+    }
+
+    fn ancestors_of<'b>(map: &Option<ContextMap>, slot_key: Option<SlotKey>)
+        -> Iter<'b, T>
+    {
+        let mut stack = Self::stack(map, slot_key);
+        stack.pop();
+        Iter::new(map, stack)
+    }
+
+    /// Immutably borrows the current object.
+    ///
+    /// Returns an error if no current object is set.
+    /// Panics if it is mutably borrowed.
+    pub fn try_get(&self) -> Result<Ref<'_, T>, NoCurrentError> {
+        Self::lookup(&self.map, self.slot_key.clone(), Ref::new)
+    }
+
+    /// Mutably borrows the current object.
+    ///
+    /// Returns an error if no current object is set.
+    /// Panics if it is already borrowed or read-only.
+    pub fn try_get_mut(&mut self) -> Result<RefMut<'_, T>, NoCurrentError> {
+        Self::lookup(&self.map, self.slot_key.clone(), RefMut::new)
+    }
+
+    /// Immutably borrows the current object.
+    ///
+    /// Panics if no current object is set or if it is mutably borrowed.
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.lookup_unwrap(Ref::new)
+    }
+
+    /// Mutably borrows the current object.
+    ///
+    /// Panics if no current object is set,
+    /// or if it is already borrowed or read-only.
+    pub fn borrow_mut(&mut self) -> RefMut<'_, T> {
+        self.lookup_unwrap(RefMut::new)
+    }
 
     /// Gets mutable reference to current object.
     /// Requires mutable reference to prevent access to globals in safe code,
     /// and to prevent mutable borrows of same value in scope.
-    /// Is unsafe because returned reference inherits lifetime from argument.
+    /// Panics if the current object is borrowed or read-only.
+    ///
+    /// # Safety
+    ///
+    /// The returned reference inherits lifetime from argument,
+    /// not from the guard that set the current object.
     pub unsafe fn current(&mut self) -> Option<&mut T> {
-        use std::mem::transmute;
-        let id = TypeId::of::<T>();
-        let ptr: Option<usize> = KEY_CURRENT.with(|current| {
-                current.borrow().get(&id).map(|id| *id)
-            });
-        let ptr = match ptr { None => { return None; } Some(x) => x };
-        Some(transmute(ptr as *mut T))
+        let slot = Self::lookup(&self.map, self.slot_key.clone(), Slot::clone);
+        slot.ok().map(|slot| {
+            check_writable(&slot);
+            &mut *slot.ptr::<T>()
+        })
     }
 
     /// Unwraps mutable reference to current object,
     /// but with nicer error message.
+    ///
+    /// # Safety
+    ///
+    /// See `current`.
     pub unsafe fn current_unwrap(&mut self) -> &mut T {
-        match self.current() {
-            None => {
-                use std::intrinsics::type_name;
-                panic!("No current `{}` is set", type_name::<T>());
-            }
-            Some(x) => x
+        let slot = self.lookup_unwrap(Slot::clone);
+        check_writable(&slot);
+        &mut *slot.ptr::<T>()
+    }
+}
+
+/// The error returned when there is no current value of a type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NoCurrentError {
+    name: Name,
+    thread_id: ThreadId,
+    thread_name: Option<String>,
+    removed: bool
+}
+
+impl NoCurrentError {
+    fn new(name: Name, removed: bool) -> NoCurrentError {
+        let thread = thread::current();
+        NoCurrentError {
+            name,
+            thread_id: thread.id(),
+            thread_name: thread.name().map(|name| name.to_string()),
+            removed
         }
     }
+
+    /// The name of the type that has no current value.
+    pub fn type_name(&self) -> &'static str { self.name.type_name }
+
+    /// The name of the tag the value was looked up under,
+    /// or `None` if it was looked up without a tag.
+    pub fn tag_name(&self) -> Option<&'static str> { self.name.tag_name }
+
+    /// The runtime key the value was looked up under,
+    /// or `None` if it was looked up without a key.
+    pub fn slot_key(&self) -> Option<&SlotKey> { self.name.slot_key.as_ref() }
+
+    /// The thread that looked up the current value.
+    pub fn thread_id(&self) -> ThreadId { self.thread_id }
+
+    /// The name of the thread that looked up the current value, if any.
+    pub fn thread_name(&self) -> Option<&str> { self.thread_name.as_deref() }
+
+    /// Whether the type had a current value that has since been removed,
+    /// as opposed to never having been set on the thread.
+    ///
+    /// Values set under a runtime key count for every key,
+    /// as long as they have the same type and tag.
+    pub fn was_removed(&self) -> bool { self.removed }
 }
 
-impl<T> Deref for Current<T> where T: Any {
-    type Target = T;
+impl fmt::Display for NoCurrentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No current {} is set on thread ", self.name)?;
+        match self.thread_name {
+            Some(ref name) => write!(f, "`{}`", name)?,
+            None => write!(f, "{:?}", self.thread_id)?
+        }
+        if self.removed {
+            write!(f, " (it was set and then removed)")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for NoCurrentError {}
+
+fn check_not_writing(borrow: &BorrowState, name: &Name) {
+    if borrow.get() == WRITING {
+        panic!("Current {} is already mutably borrowed", name);
+    }
+}
+
+fn check_unused(borrow: &BorrowState, name: &Name) {
+    check_not_writing(borrow, name);
+    if borrow.get() != UNUSED {
+        panic!("Current {} is already borrowed", name);
+    }
+}
+
+fn check_writable(slot: &Slot) {
+    if slot.read_only {
+        panic!("Current {} is read-only", slot.name);
+    }
+    check_unused(&slot.borrow, &slot.name);
+}
+
+/// Iterates over the stack of objects set for a type,
+/// from the innermost to the outermost.
+pub struct Iter<'b, T> where T: ?Sized + Any {
+    map: Option<ContextMap>,
+    slots: vec::IntoIter<Slot>,
+    _marker: PhantomData<&'b T>
+}
 
-    #[inline(always)]
-    fn deref<'a>(&'a self) -> &'a T {
-        use std::mem::transmute;
-        unsafe {
-            // Current does not contain anything,
-            // so it is safe to transmute to mutable.
-            transmute::<_, &'a mut Current<T>>(self).current_unwrap()
+impl<'b, T> Iter<'b, T> where T: ?Sized + Any {
+    fn new(map: &Option<ContextMap>, slots: Vec<Slot>) -> Iter<'b, T> {
+        Iter {
+            map: map.as_ref().map(ContextMap::share),
+            slots: slots.into_iter(),
+            _marker: PhantomData
         }
     }
 }
 
-impl<T> DerefMut for Current<T> where T: Any {
-    #[inline(always)]
-    fn deref_mut<'a>(&'a mut self) -> &'a mut T {
-        unsafe { self.current_unwrap() }
+impl<'b, T> Iterator for Iter<'b, T> where T: ?Sized + Any {
+    type Item = Ref<'b, T>;
+
+    fn next(&mut self) -> Option<Ref<'b, T>> {
+        // Values may be dropped while iterating, so skip those gone since.
+        let map = self.map.as_ref()?;
+        self.slots.by_ref().rev()
+            .find_map(|slot| map.if_alive(&slot, Ref::new))
+    }
+
+    // Values may be dropped without calling `next`,
+    // so there is no lower bound.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.slots.len()))
+    }
+}
+
+/// A shared borrow of a current object.
+pub struct Ref<'b, T> where T: ?Sized + Any {
+    val: &'b T,
+    borrow: BorrowFlag
+}
+
+impl<'b, T> Ref<'b, T> where T: ?Sized + Any {
+    fn new(slot: &Slot) -> Ref<'b, T> {
+        Ref::acquire(slot.ptr::<T>(), slot.borrow.clone(), &slot.name)
+    }
+
+    fn acquire(ptr: *const T, borrow: BorrowFlag, name: &Name) -> Ref<'b, T> {
+        borrow.acquire(name);
+        Ref { val: unsafe { &*ptr }, borrow }
+    }
+}
+
+impl<'b, T> Deref for Ref<'b, T> where T: ?Sized + Any {
+    type Target = T;
+
+    fn deref(&self) -> &T { self.val }
+}
+
+impl<'b, T> Drop for Ref<'b, T> where T: ?Sized + Any {
+    fn drop(&mut self) {
+        self.borrow.release();
+    }
+}
+
+/// A mutable borrow of a current object.
+pub struct RefMut<'b, T> where T: ?Sized + Any {
+    val: &'b mut T,
+    borrow: BorrowFlag
+}
+
+impl<'b, T> RefMut<'b, T> where T: ?Sized + Any {
+    fn new(slot: &Slot) -> RefMut<'b, T> {
+        check_writable(slot);
+        RefMut::acquire(slot.ptr::<T>(), slot.borrow.clone(), &slot.name)
+    }
+
+    fn acquire(ptr: *mut T, borrow: BorrowFlag, name: &Name) -> RefMut<'b, T> {
+        borrow.acquire_mut(name);
+        RefMut { val: unsafe { &mut *ptr }, borrow }
+    }
+}
+
+impl<'b, T> Deref for RefMut<'b, T> where T: ?Sized + Any {
+    type Target = T;
+
+    fn deref(&self) -> &T { self.val }
+}
+
+impl<'b, T> DerefMut for RefMut<'b, T> where T: ?Sized + Any {
+    fn deref_mut(&mut self) -> &mut T { self.val }
+}
+
+impl<'b, T> Drop for RefMut<'b, T> where T: ?Sized + Any {
+    fn drop(&mut self) {
+        self.borrow.release_mut();
     }
 }
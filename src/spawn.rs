@@ -0,0 +1,108 @@
+//! Carrying current values over to other threads.
+
+use std::any::Any;
+use std::thread::{ self, JoinHandle, Scope, ScopedJoinHandle };
+
+use { Current, CurrentBox };
+
+// Makes a captured value current, returning the box that keeps it current.
+type Install = Box<dyn FnOnce() -> Box<dyn Any> + Send>;
+
+// Keeps installed values current, dropping them innermost first.
+struct Installs(Vec<Box<dyn Any>>);
+
+impl Drop for Installs {
+    fn drop(&mut self) {
+        while let Some(installed) = self.0.pop() {
+            drop(installed);
+        }
+    }
+}
+
+/// A set of values to make current in another thread.
+///
+/// Values are captured by clone or by move on the spawning thread,
+/// so to share a value between threads, make an `Arc` of it current
+/// and capture the `Arc`.
+/// The values are made current in the order they were captured,
+/// each owned by the receiving thread for as long as its closure runs.
+pub struct Currents {
+    installs: Vec<Install>
+}
+
+impl Default for Currents {
+    fn default() -> Currents {
+        Currents::new()
+    }
+}
+
+impl Currents {
+    /// Creates an empty set of values.
+    pub fn new() -> Currents {
+        Currents { installs: vec![] }
+    }
+
+    /// Captures a clone of the current object of type `T`.
+    ///
+    /// Nothing is captured if no current object of type `T` is set.
+    /// Panics if the current object is mutably borrowed.
+    pub fn clone_current<T>(self) -> Currents where T: Any + Clone + Send {
+        self.clone_current_tagged::<T, ()>()
+    }
+
+    /// Captures a clone of the current object of type `T` under a tag.
+    pub fn clone_current_tagged<T, Tag>(self) -> Currents
+        where T: Any + Clone + Send, Tag: Any
+    {
+        match Current::<T, Tag>::with(T::clone) {
+            None => self,
+            Some(val) => self.value_tagged::<T, Tag>(val)
+        }
+    }
+
+    /// Captures a value to make current.
+    pub fn value<T>(self, val: T) -> Currents where T: Any + Send {
+        self.value_tagged::<T, ()>(val)
+    }
+
+    /// Captures a value to make current under a tag.
+    pub fn value_tagged<T, Tag>(mut self, val: T) -> Currents
+        where T: Any + Send, Tag: Any
+    {
+        self.installs.push(Box::new(move || {
+            Box::new(CurrentBox::<T, Tag>::new_tagged(val)) as Box<dyn Any>
+        }));
+        self
+    }
+
+    /// Makes the captured values current while calling a closure.
+    pub fn scope<F, R>(self, f: F) -> R where F: FnOnce() -> R {
+        let mut installs = Installs(Vec::with_capacity(self.installs.len()));
+        for install in self.installs {
+            installs.0.push(install());
+        }
+        f()
+    }
+}
+
+/// Spawns a thread with the captured values current.
+///
+/// See `std::thread::spawn`.
+pub fn spawn_with_currents<F, R>(currents: Currents, f: F) -> JoinHandle<R>
+    where F: FnOnce() -> R + Send + 'static, R: Send + 'static
+{
+    thread::spawn(move || currents.scope(f))
+}
+
+/// Spawns a scoped thread with the captured values current.
+///
+/// See `std::thread::Scope::spawn`.
+pub fn spawn_scoped_with_currents<'scope, 'env, F, R>(
+    scope: &'scope Scope<'scope, 'env>,
+    currents: Currents,
+    f: F
+) -> ScopedJoinHandle<'scope, R>
+    where F: FnOnce() -> R + Send + 'scope, R: Send + 'scope
+{
+    scope.spawn(move || currents.scope(f))
+}
@@ -0,0 +1,71 @@
+//! Borrowing several current values together.
+
+use std::any::{ Any, TypeId, type_name };
+
+use { Current, NoCurrentError, RefMut };
+
+/// Calls a closure with mutable references to current objects
+/// of several types, such as `|gun: &mut Gun, player: &mut Player| ...`.
+///
+/// Returns `None` if any of the objects is not set.
+/// Panics if the same type is asked for twice,
+/// or if an object is already borrowed or was set from a shared reference.
+/// Views of one value set with `CurrentBuilder` share a borrow,
+/// so asking for two views of the same value panics as well.
+pub fn with_currents<F, Args, R>(f: F) -> Option<R>
+    where F: CurrentsFn<Args, R>
+{
+    f.call_with_currents().ok()
+}
+
+/// Calls a closure with mutable references to current objects
+/// of several types.
+///
+/// Returns an error naming the first object that is not set.
+/// See `with_currents`.
+pub fn try_with_currents<F, Args, R>(f: F) -> Result<R, NoCurrentError>
+    where F: CurrentsFn<Args, R>
+{
+    f.call_with_currents()
+}
+
+/// A closure taking mutable references to current objects,
+/// where `Args` is the function pointer type of the closure.
+pub trait CurrentsFn<Args, R> {
+    /// Calls the closure with the current objects.
+    fn call_with_currents(self) -> Result<R, NoCurrentError>;
+}
+
+// Panics if a type is asked for twice.
+fn check_distinct(types: &[(TypeId, &'static str)]) {
+    for (i, &(id, name)) in types.iter().enumerate() {
+        if types[..i].iter().any(|&(other, _)| other == id) {
+            panic!("Current `{}` is borrowed twice by `with_currents`", name);
+        }
+    }
+}
+
+macro_rules! currents_fn {
+    ($($name:ident),+) => {
+        impl<Func, R, $($name),+> CurrentsFn<fn($(&mut $name),+), R> for Func
+            where Func: FnOnce($(&mut $name),+) -> R,
+                  $($name: ?Sized + Any),+
+        {
+            #[allow(non_snake_case)]
+            fn call_with_currents(self) -> Result<R, NoCurrentError> {
+                $(let $name = Current::<$name>::try_slot(None)?;)+
+                check_distinct(&[
+                    $((TypeId::of::<$name>(), type_name::<$name>())),+
+                ]);
+                $(let mut $name = RefMut::<$name>::new(&$name);)+
+                Ok(self($(&mut *$name),+))
+            }
+        }
+    }
+}
+
+currents_fn!(A, B);
+currents_fn!(A, B, C);
+currents_fn!(A, B, C, D);
+currents_fn!(A, B, C, D, E);
+currents_fn!(A, B, C, D, E, F);
@@ -0,0 +1,89 @@
+extern crate current;
+
+use current::{ Context, Current, CurrentBox, CurrentGuard, SharedGuard };
+
+struct Theme {
+    name: &'static str
+}
+
+struct Volume(u8);
+
+fn theme() -> Option<&'static str> {
+    Current::<Theme>::with(|theme| theme.name)
+}
+
+#[test]
+fn enter_captured_context() {
+    let mut dark = Theme { name: "dark" };
+    let mut volume = Volume(3);
+    let context = CurrentGuard::scope(&mut dark, || {
+        CurrentGuard::scope(&mut volume, || {
+            let context = Context::capture();
+            assert_eq!(context.len(), 2);
+            context
+        })
+    });
+    assert_eq!(context.len(), 0);
+    context.enter(|| assert_eq!(theme(), None));
+
+    let mut light = Theme { name: "light" };
+    CurrentGuard::scope(&mut light, || {
+        let context = Context::new().include::<Theme>();
+        let mut other = Theme { name: "other" };
+        CurrentGuard::scope(&mut other, || {
+            assert_eq!(theme(), Some("other"));
+            context.enter(|| {
+                assert_eq!(theme(), Some("light"));
+                assert_eq!(Current::<Theme>::depth(), 3);
+                Current::<Theme>::with_mut(|theme| theme.name = "changed");
+            });
+            assert_eq!(theme(), Some("other"));
+        });
+    });
+    assert_eq!(light.name, "changed");
+}
+
+#[test]
+fn context_skips_dropped_values() {
+    let boxed = CurrentBox::new(Volume(5));
+    let context = Context::capture();
+    context.enter(move || {
+        assert_eq!(Current::<Volume>::with(|volume| volume.0), Some(5));
+        drop(boxed);
+        assert_eq!(Current::<Volume>::with(|volume| volume.0), None);
+        assert_eq!(Current::<Volume>::depth(), 0);
+    });
+    assert!(context.is_empty());
+}
+
+#[test]
+#[should_panic(expected = "is read-only")]
+fn context_keeps_read_only() {
+    let theme = Theme { name: "shared" };
+    let mut other = Theme { name: "other" };
+    SharedGuard::scope(&theme, || {
+        let context = Context::capture();
+        CurrentGuard::scope(&mut other, || {
+            context.enter(|| {
+                Current::<Theme>::with_mut(|theme| theme.name = "changed");
+            });
+        });
+    });
+}
+
+#[test]
+fn bound_closure_sees_bind_time_values() {
+    let mut dark = Theme { name: "dark" };
+    let mut light = Theme { name: "light" };
+    let mut names = vec![];
+    CurrentGuard::scope(&mut dark, || {
+        let mut on_event = current::bind_arg(|count: usize| {
+            names.push((count, theme()));
+        });
+        CurrentGuard::scope(&mut light, || on_event(1));
+        on_event(2);
+        let mut depth = current::bind(Current::<Theme>::depth);
+        assert_eq!(depth(), 2);
+    });
+    assert_eq!(names, vec![(1, Some("dark")), (2, Some("dark"))]);
+}
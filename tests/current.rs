@@ -0,0 +1,524 @@
+#[macro_use]
+extern crate current;
+
+use current::{
+    Current, CurrentBox, CurrentGuard, CurrentGuards, GuardStack,
+    NoCurrentError, SharedGuard, SlotKey, with_currents
+};
+
+struct Foo {
+    text: String
+}
+
+fn current_text() -> Option<String> {
+    Current::<Foo>::with(|val| val.text.clone())
+}
+
+fn set_current_text(text: &str) {
+    let mut current = unsafe { Current::<Foo>::new() };
+    current.borrow_mut().text = text.to_string();
+}
+
+#[test]
+fn not_set_by_default() {
+    assert_eq!(current_text(), None);
+}
+
+#[test]
+fn guard_sets_and_removes_current() {
+    let mut val = Foo { text: "hello".to_string() };
+    {
+        let _guard = unsafe { CurrentGuard::new(&mut val) };
+        assert_eq!(current_text(), Some("hello".to_string()));
+        set_current_text("world!");
+        assert_eq!(current_text(), Some("world!".to_string()));
+    }
+    assert_eq!(current_text(), None);
+    assert_eq!(val.text, "world!");
+}
+
+#[test]
+fn nested_guard_restores_previous() {
+    let mut outer = Foo { text: "hello".to_string() };
+    let mut inner = Foo { text: "good bye".to_string() };
+    let guard = unsafe { CurrentGuard::new(&mut outer) };
+    {
+        let _guard = unsafe { CurrentGuard::new(&mut inner) };
+        assert_eq!(current_text(), Some("good bye".to_string()));
+        set_current_text("world!");
+    }
+    assert_eq!(current_text(), Some("hello".to_string()));
+    drop(guard);
+    assert_eq!(current_text(), None);
+    assert_eq!(inner.text, "world!");
+    assert_eq!(outer.text, "hello");
+}
+
+#[test]
+fn current_is_per_thread() {
+    let mut val = Foo { text: "hello".to_string() };
+    let _guard = unsafe { CurrentGuard::new(&mut val) };
+    let other = std::thread::spawn(current_text).join().unwrap();
+    assert_eq!(other, None);
+}
+
+#[test]
+#[should_panic(expected = "No current `current::Foo` is set")]
+fn borrow_panics_with_type_name() {
+    let current = unsafe { Current::<Foo>::new() };
+    let _ = &current.borrow().text;
+}
+
+#[test]
+fn with_is_none_when_not_set() {
+    assert_eq!(Current::<Foo>::with(|val| val.text.clone()), None);
+    assert_eq!(Current::<Foo>::with_mut(|val| val.text.clear()), None);
+}
+
+#[test]
+fn with_and_with_mut_access_current() {
+    let mut val = Foo { text: "hello".to_string() };
+    CurrentGuard::scope(&mut val, || {
+        Current::<Foo>::with_mut(|val| val.text.push_str(" world"));
+        assert_eq!(Current::<Foo>::with(|val| val.text.clone()),
+                   Some("hello world".to_string()));
+    });
+    assert_eq!(val.text, "hello world");
+}
+
+#[test]
+fn with_allows_nested_shared_borrows() {
+    let mut val = Foo { text: "hello".to_string() };
+    let _guard = unsafe { CurrentGuard::new(&mut val) };
+    let nested = Current::<Foo>::with(|_| {
+        Current::<Foo>::with(|val| val.text.clone())
+    });
+    assert_eq!(nested, Some(Some("hello".to_string())));
+}
+
+#[test]
+#[should_panic(expected = "Current `current::Foo` is already mutably borrowed")]
+fn with_can_not_borrow_twice() {
+    let mut val = Foo { text: "hello".to_string() };
+    let _guard = unsafe { CurrentGuard::new(&mut val) };
+    Current::<Foo>::with_mut(|_| {
+        Current::<Foo>::with(|val| val.text.clone())
+    });
+}
+
+#[test]
+fn borrows_are_released() {
+    let mut val = Foo { text: "hello".to_string() };
+    let _guard = unsafe { CurrentGuard::new(&mut val) };
+    let mut current = unsafe { Current::<Foo>::new() };
+    {
+        let first = current.borrow();
+        let second = unsafe { Current::<Foo>::new() };
+        assert_eq!(first.text, second.borrow().text);
+    }
+    current.borrow_mut().text = "world!".to_string();
+    assert_eq!(current.borrow().text, "world!");
+}
+
+#[test]
+#[should_panic(expected = "Current `current::Foo` is already borrowed")]
+fn aliasing_handles_are_detected() {
+    let mut val = Foo { text: "hello".to_string() };
+    let _guard = unsafe { CurrentGuard::new(&mut val) };
+    let first = unsafe { Current::<Foo>::new() };
+    let mut second = unsafe { Current::<Foo>::new() };
+    let _shared = first.borrow();
+    second.borrow_mut().text = "world!".to_string();
+}
+
+#[test]
+fn shadowed_value_keeps_its_borrow() {
+    let mut outer = Foo { text: "hello".to_string() };
+    let mut inner = Foo { text: "good bye".to_string() };
+    CurrentGuard::scope(&mut outer, || {
+        Current::<Foo>::with_mut(|_| {
+            CurrentGuard::scope(&mut inner, || {
+                Current::<Foo>::with_mut(|val| val.text.push('!'));
+            });
+        });
+    });
+    assert_eq!(inner.text, "good bye!");
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "was dropped before the guard created at")]
+fn out_of_order_drop_panics_in_debug() {
+    let mut outer = Foo { text: "hello".to_string() };
+    let mut inner = Foo { text: "good bye".to_string() };
+    let outer_guard = unsafe { CurrentGuard::new(&mut outer) };
+    let _inner_guard = unsafe { CurrentGuard::new(&mut inner) };
+    drop(outer_guard);
+}
+
+#[test]
+fn scope_removes_current_on_panic() {
+    let mut val = Foo { text: "hello".to_string() };
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        CurrentGuard::scope(&mut val, || panic!("oops"));
+    }));
+    assert!(result.is_err());
+    assert_eq!(current_text(), None);
+}
+
+struct NeverSet;
+
+fn current_len() -> Result<usize, NoCurrentError> {
+    let len = Current::<Foo>::try_with(|val| val.text.len())?;
+    Ok(len)
+}
+
+#[test]
+fn try_with_reports_missing_type() {
+    let err = Current::<NeverSet>::try_with(|_| ()).unwrap_err();
+    assert_eq!(err.type_name(), "current::NeverSet");
+    assert_eq!(err.thread_id(), std::thread::current().id());
+    assert!(!err.was_removed());
+    assert!(err.to_string().starts_with("No current `current::NeverSet` is set"));
+}
+
+#[test]
+fn try_with_reports_removed_type() {
+    let mut val = Foo { text: "hello".to_string() };
+    assert_eq!(CurrentGuard::scope(&mut val, current_len), Ok(5));
+    let err = current_len().unwrap_err();
+    assert!(err.was_removed());
+    assert!(err.to_string().ends_with("(it was set and then removed)"));
+}
+
+#[test]
+fn try_get_borrows_current() {
+    let mut val = Foo { text: "hello".to_string() };
+    let mut current = unsafe { Current::<Foo>::new() };
+    assert!(current.try_get().is_err());
+    CurrentGuard::scope(&mut val, || {
+        current.try_get_mut().unwrap().text.push('!');
+        assert_eq!(current.try_get().unwrap().text, "hello!");
+    });
+}
+
+#[test]
+fn current_box_owns_value() {
+    let mut outer = Foo { text: "hello".to_string() };
+    CurrentGuard::scope(&mut outer, || {
+        let boxed = CurrentBox::new(Foo { text: "good bye".to_string() });
+        set_current_text("world!");
+        assert_eq!(current_text(), Some("world!".to_string()));
+        let val = boxed.into_inner();
+        assert_eq!(val.text, "world!");
+        assert_eq!(current_text(), Some("hello".to_string()));
+    });
+}
+
+#[test]
+fn current_box_restores_on_drop() {
+    {
+        let val = Box::new(Foo { text: "hello".to_string() });
+        let _boxed = CurrentBox::from_box(val);
+        assert_eq!(current_text(), Some("hello".to_string()));
+    }
+    assert_eq!(current_text(), None);
+}
+
+#[test]
+fn leaked_current_box_stays_valid() {
+    std::mem::forget(CurrentBox::new(Foo { text: "hello".to_string() }));
+    assert_eq!(current_text(), Some("hello".to_string()));
+}
+
+#[test]
+fn borrowed_current_box_is_not_freed() {
+    use std::panic::{ catch_unwind, AssertUnwindSafe };
+
+    let boxed = CurrentBox::new(Foo { text: "hello".to_string() });
+    let text = Current::<Foo>::with(move |val| {
+        let result = catch_unwind(AssertUnwindSafe(|| boxed.into_inner()));
+        assert!(result.is_err());
+        val.text.clone()
+    });
+    assert_eq!(text, Some("hello".to_string()));
+
+    let boxed = CurrentBox::new(Foo { text: "good bye".to_string() });
+    let text = Current::<Foo>::with(move |val| {
+        let result = catch_unwind(AssertUnwindSafe(move || {
+            let _boxed = boxed;
+            panic!("dropping the box while unwinding");
+        }));
+        assert!(result.is_err());
+        val.text.clone()
+    });
+    assert_eq!(text, Some("good bye".to_string()));
+    assert_eq!(current_text(), None);
+}
+
+#[test]
+fn shared_guard_allows_reads() {
+    let val = Foo { text: "hello".to_string() };
+    let text = &val.text;
+    SharedGuard::scope(&val, || {
+        assert_eq!(current_text().as_ref(), Some(text));
+    });
+    assert_eq!(current_text(), None);
+}
+
+#[test]
+#[should_panic(expected = "Current `current::Foo` is read-only")]
+fn shared_guard_rejects_writes() {
+    let val = Foo { text: "hello".to_string() };
+    SharedGuard::scope(&val, || {
+        Current::<Foo>::with_mut(|val| val.text.clear());
+    });
+}
+
+#[test]
+fn guard_gives_access_to_value() {
+    let mut val = Foo { text: "hello".to_string() };
+    let mut guard = unsafe { CurrentGuard::new(&mut val) };
+    guard.get_mut().text.push('!');
+    assert_eq!(guard.get().text, "hello!");
+    assert_eq!(current_text(), Some("hello!".to_string()));
+}
+
+#[test]
+#[should_panic(expected = "Current `current::Foo` is already borrowed")]
+fn guard_borrows_are_tracked() {
+    let mut val = Foo { text: "hello".to_string() };
+    let mut guard = unsafe { CurrentGuard::new(&mut val) };
+    Current::<Foo>::with(|_| {
+        guard.get_mut().text.clear();
+    });
+}
+
+struct Debug;
+
+fn current_debug_text() -> Option<String> {
+    Current::<Foo, Debug>::with(|val| val.text.clone())
+}
+
+#[test]
+fn tagged_values_are_independent() {
+    let mut main = Foo { text: "main".to_string() };
+    let mut debug = Foo { text: "debug".to_string() };
+    CurrentGuard::scope(&mut main, || {
+        assert_eq!(current_debug_text(), None);
+        CurrentGuard::<_, Debug>::scope_tagged(&mut debug, || {
+            assert_eq!(current_text(), Some("main".to_string()));
+            assert_eq!(current_debug_text(), Some("debug".to_string()));
+        });
+        assert_eq!(current_debug_text(), None);
+    });
+}
+
+#[test]
+fn tagged_error_names_tag() {
+    let err = Current::<Foo, Debug>::try_with(|_| ()).unwrap_err();
+    assert_eq!(err.tag_name(), Some("current::Debug"));
+    assert!(err.to_string()
+        .starts_with("No current `current::Foo` tagged `current::Debug` is set"));
+}
+
+fn named_text<K: Into<SlotKey>>(key: K) -> Option<String> {
+    Current::<Foo>::with_named(key, |val| val.text.clone())
+}
+
+#[test]
+fn named_values_are_independent() {
+    let mut hud = Foo { text: "hud".to_string() };
+    let mut player = Foo { text: "player".to_string() };
+    CurrentGuard::scope_named("hud", &mut hud, || {
+        CurrentGuard::scope_named(1, &mut player, || {
+            assert_eq!(current_text(), None);
+            assert_eq!(named_text("hud"), Some("hud".to_string()));
+            Current::<Foo>::with_named_mut(1, |val| val.text.push('!'));
+            let current = unsafe { Current::<Foo>::named(1) };
+            assert_eq!(current.borrow().text, "player!");
+        });
+        let err = Current::<Foo>::try_with_named(1, |_| ()).unwrap_err();
+        assert!(err.was_removed());
+        assert!(err.to_string()
+            .starts_with("No current `current::Foo` named `1` is set"));
+    });
+}
+
+#[test]
+fn removed_named_values_count_for_every_key() {
+    let mut val = Foo { text: "hello".to_string() };
+    for key in 0..100u64 {
+        CurrentGuard::scope_named(key, &mut val, || ());
+    }
+    let err = Current::<Foo>::try_with_named(100, |_| ()).unwrap_err();
+    assert!(err.was_removed());
+}
+
+#[test]
+fn named_values_stack() {
+    let mut outer = Foo { text: "outer".to_string() };
+    let mut inner = Foo { text: "inner".to_string() };
+    let key = "viewport".to_string();
+    CurrentGuard::scope_named(key.clone(), &mut outer, || {
+        CurrentGuard::scope_named(key.clone(), &mut inner, || {
+            assert_eq!(named_text(key.clone()), Some("inner".to_string()));
+        });
+        assert_eq!(named_text(key.clone()), Some("outer".to_string()));
+    });
+}
+
+#[test]
+fn named_values_under_tag() {
+    let mut val = Foo { text: "debug hud".to_string() };
+    CurrentGuard::<Foo, Debug>::scope_named_tagged("hud", &mut val, || {
+        assert_eq!(named_text("hud"), None);
+        let text = Current::<Foo, Debug>::with_named("hud", |val| {
+            val.text.clone()
+        });
+        assert_eq!(text, Some("debug hud".to_string()));
+    });
+}
+
+fn stack_texts() -> Vec<String> {
+    Current::<Foo>::with_stack(|stack| {
+        stack.map(|val| val.text.clone()).collect()
+    })
+}
+
+#[test]
+fn stack_lists_shadowed_values() {
+    let mut outer = Foo { text: "outer".to_string() };
+    let mut inner = Foo { text: "inner".to_string() };
+    assert_eq!(Current::<Foo>::depth(), 0);
+    assert!(stack_texts().is_empty());
+    CurrentGuard::scope(&mut outer, || {
+        assert_eq!(Current::<Foo>::depth(), 1);
+        CurrentGuard::scope(&mut inner, || {
+            assert_eq!(Current::<Foo>::depth(), 2);
+            assert_eq!(stack_texts(), vec!["inner", "outer"]);
+            let current = unsafe { Current::<Foo>::new() };
+            assert_eq!(current.iter().count(), 2);
+        });
+    });
+    assert_eq!(Current::<Foo>::depth(), 0);
+}
+
+#[test]
+#[should_panic(expected = "is already mutably borrowed")]
+fn stack_follows_borrow_rules() {
+    let mut val = Foo { text: "hello".to_string() };
+    CurrentGuard::scope(&mut val, || {
+        Current::<Foo>::with_mut(|_| stack_texts());
+    });
+}
+
+#[test]
+fn stack_skips_values_dropped_while_iterating() {
+    let mut outer = Foo { text: "outer".to_string() };
+    CurrentGuard::scope(&mut outer, || {
+        let boxed = CurrentBox::new(Foo { text: "boxed".to_string() });
+        let texts: Vec<String> = Current::<Foo>::with_stack(move |stack| {
+            assert_eq!(stack.size_hint(), (0, Some(2)));
+            drop(boxed);
+            stack.map(|val| val.text.clone()).collect()
+        });
+        assert_eq!(texts, vec!["outer"]);
+    });
+}
+
+#[test]
+fn parent_combines_with_current() {
+    let mut outer = Foo { text: "outer".to_string() };
+    let mut middle = Foo { text: "middle".to_string() };
+    let mut inner = Foo { text: "inner".to_string() };
+    assert_eq!(Current::<Foo>::with_parent(|_| ()), None);
+    CurrentGuard::scope(&mut outer, || {
+        assert_eq!(Current::<Foo>::with_parent(|_| ()), None);
+        CurrentGuard::scope(&mut middle, || {
+            CurrentGuard::scope(&mut inner, || {
+                let joined = Current::<Foo>::with_mut(|val| {
+                    Current::<Foo>::with_parent(|parent| {
+                        val.text.push_str(&parent.text);
+                        val.text.clone()
+                    })
+                });
+                assert_eq!(joined, Some(Some("innermiddle".to_string())));
+                let ancestors: Vec<String> = Current::<Foo>::with_ancestors(
+                    |ancestors| ancestors.map(|val| val.text.clone()).collect()
+                );
+                assert_eq!(ancestors, vec!["middle", "outer"]);
+                let current = unsafe { Current::<Foo>::new() };
+                assert_eq!(current.parent().unwrap().text, "middle");
+                assert_eq!(current.ancestors().count(), 2);
+            });
+        });
+    });
+}
+
+#[test]
+fn tuple_guard_sets_several() {
+    let mut val = Foo { text: "hello".to_string() };
+    let mut count = 0u32;
+    let mut other = Foo { text: "other".to_string() };
+    CurrentGuards::scope((&mut val, &mut count, &mut other), || {
+        assert_eq!(current_text(), Some("other".to_string()));
+        assert_eq!(Current::<Foo>::depth(), 2);
+        Current::<u32>::with_mut(|count| *count += 1);
+    });
+    assert_eq!(Current::<Foo>::depth(), 0);
+    assert_eq!(count, 1);
+    let count = set_currents!((val, count) => {
+        set_current_text("macro");
+        Current::<u32>::with(|count| *count)
+    });
+    assert_eq!(count, Some(1));
+    assert_eq!(current_text(), None);
+    assert_eq!(val.text, "macro");
+}
+
+#[test]
+fn with_currents_borrows_several() {
+    let mut val = Foo { text: "aim".to_string() };
+    let mut shots = 0u32;
+    let shoot = |shots: &mut u32, val: &mut Foo| {
+        *shots += 1;
+        val.text.len()
+    };
+    assert_eq!(with_currents(shoot), None);
+    CurrentGuards::scope((&mut val, &mut shots), || {
+        assert_eq!(with_currents(shoot), Some(3));
+        assert_eq!(with_currents(|_: &mut u32, _: &mut Foo, _: &mut u8| ()),
+                   None);
+    });
+    assert_eq!(shots, 1);
+}
+
+#[test]
+#[should_panic(expected = "borrowed twice")]
+fn with_currents_rejects_same_type() {
+    let mut val = Foo { text: "hello".to_string() };
+    CurrentGuard::scope(&mut val, || {
+        with_currents(|_: &mut Foo, _: &mut Foo| ());
+    });
+}
+
+#[test]
+fn guard_stack_pops_in_reverse() {
+    let mut vals: Vec<Foo> = ["a", "b", "c"].iter()
+        .map(|text| Foo { text: text.to_string() })
+        .collect();
+    GuardStack::scope(|stack| {
+        for val in &mut vals {
+            stack.push(val);
+        }
+        stack.push_guard(CurrentBox::new(7u32));
+        assert_eq!(stack.len(), 4);
+        assert_eq!(stack_texts(), vec!["c", "b", "a"]);
+        assert!(stack.pop());
+        assert_eq!(Current::<u32>::with(|val| *val), None);
+        assert!(stack.pop());
+        assert_eq!(current_text(), Some("b".to_string()));
+    });
+    assert_eq!(current_text(), None);
+}
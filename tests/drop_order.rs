@@ -0,0 +1,27 @@
+extern crate current;
+
+use current::{ Current, CurrentGuard, OutOfOrder };
+
+struct Foo {
+    text: String
+}
+
+fn current_text() -> Option<String> {
+    Current::<Foo>::with(|val| val.text.clone())
+}
+
+// Kept as a single test, since the policy is shared by all threads.
+#[test]
+fn out_of_order_drop_is_repaired() {
+    OutOfOrder::set(OutOfOrder::Repair);
+    assert_eq!(OutOfOrder::get(), OutOfOrder::Repair);
+
+    let mut outer = Foo { text: "hello".to_string() };
+    let mut inner = Foo { text: "good bye".to_string() };
+    let outer_guard = unsafe { CurrentGuard::new(&mut outer) };
+    let inner_guard = unsafe { CurrentGuard::new(&mut inner) };
+    drop(outer_guard);
+    assert_eq!(current_text(), Some("good bye".to_string()));
+    drop(inner_guard);
+    assert_eq!(current_text(), None);
+}
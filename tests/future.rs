@@ -0,0 +1,119 @@
+extern crate current;
+
+use current::{ Current, CurrentBox, TaskLocal, WithCurrentExt };
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ Context, Poll, Wake, Waker };
+use std::thread;
+
+struct Request {
+    id: u32
+}
+
+struct Unpark(thread::Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park()
+        }
+    }
+}
+
+fn request_id() -> Option<u32> {
+    Current::<Request>::with(|request| request.id)
+}
+
+// Reads the request id, yields once, then reads it again.
+struct Handle {
+    before: Option<Option<u32>>
+}
+
+fn handle() -> Handle {
+    Handle { before: None }
+}
+
+impl Future for Handle {
+    type Output = (Option<u32>, Option<u32>);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<(Option<u32>, Option<u32>)>
+    {
+        match self.before {
+            Some(before) => Poll::Ready((before, request_id())),
+            None => {
+                self.before = Some(request_id());
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[test]
+fn current_during_poll() {
+    let ids = block_on(handle().with_current(Request { id: 7 }));
+    assert_eq!(ids, (Some(7), Some(7)));
+    assert_eq!(request_id(), None);
+}
+
+#[test]
+fn currents_do_not_leak_between_tasks() {
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut first = Box::pin(handle().with_current(Request { id: 1 }));
+    let mut second = Box::pin(handle().with_current(Request { id: 2 }));
+    assert!(first.as_mut().poll(&mut cx).is_pending());
+    assert_eq!(request_id(), None);
+    assert!(second.as_mut().poll(&mut cx).is_pending());
+    assert_eq!(first.as_mut().poll(&mut cx), Poll::Ready((Some(1), Some(1))));
+    assert_eq!(second.as_mut().poll(&mut cx), Poll::Ready((Some(2), Some(2))));
+}
+
+// Sets a task local value on the first poll and reads it on the second.
+struct KeepTaskLocal {
+    id: u32,
+    kept: Option<CurrentBox<u32, (), TaskLocal>>
+}
+
+impl Future for KeepTaskLocal {
+    type Output = Option<u32>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<Option<u32>>
+    {
+        if self.kept.is_some() {
+            return Poll::Ready(Current::<u32, (), TaskLocal>::with(|id| *id));
+        }
+        let id = self.id;
+        self.kept = Some(CurrentBox::new_tagged(id));
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test]
+fn task_local_kept_between_polls() {
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let keep = |id| KeepTaskLocal { id, kept: None }.task_scope();
+    let mut first = Box::pin(keep(1));
+    let mut second = Box::pin(keep(2));
+    assert!(first.as_mut().poll(&mut cx).is_pending());
+    assert!(second.as_mut().poll(&mut cx).is_pending());
+    assert_eq!(Current::<u32, (), TaskLocal>::with(|id| *id), None);
+    assert_eq!(first.map().depth::<u32>(), 1);
+    assert_eq!(second.as_mut().poll(&mut cx), Poll::Ready(Some(2)));
+    assert_eq!(first.as_mut().poll(&mut cx), Poll::Ready(Some(1)));
+}
@@ -0,0 +1,116 @@
+extern crate current;
+
+use current::{
+    Current, CurrentGuard, CurrentStorage, Global, GlobalCurrent,
+    GlobalCurrentGuard
+};
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+struct Config {
+    name: &'static str
+}
+
+struct Counter(AtomicUsize);
+
+struct Layer(u32);
+
+struct Gauge(u32);
+
+struct Done(AtomicBool);
+
+fn layer() -> Option<u32> {
+    GlobalCurrent::<Layer>::with(|layer| layer.0)
+}
+
+#[test]
+fn global_visible_from_other_threads() {
+    let config = Config { name: "main" };
+    GlobalCurrentGuard::scope(&config, || {
+        assert_eq!(Current::<Config>::with(|config| config.name), None);
+        let name = thread::spawn(|| {
+            GlobalCurrent::<Config>::with(|config| config.name)
+        }).join().unwrap();
+        assert_eq!(name, Some("main"));
+    });
+    assert!(GlobalCurrent::<Config>::try_with(|_| ()).unwrap_err()
+        .was_removed());
+}
+
+#[test]
+fn global_shared_mutation() {
+    let counter = Counter(AtomicUsize::new(0));
+    GlobalCurrentGuard::scope(&counter, || {
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| GlobalCurrent::<Counter>::with(|counter| {
+                    counter.0.fetch_add(1, Ordering::SeqCst)
+                }));
+            }
+        });
+    });
+    assert_eq!(counter.0.into_inner(), 4);
+}
+
+#[test]
+fn global_guards_on_different_threads() {
+    let outer = Layer(1);
+    let (set_tx, set_rx) = mpsc::channel();
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    GlobalCurrentGuard::scope(&outer, || {
+        assert_eq!(layer(), Some(1));
+        let worker = thread::spawn(move || {
+            let inner = Layer(2);
+            GlobalCurrentGuard::scope(&inner, || {
+                set_tx.send(()).unwrap();
+                done_rx.recv().unwrap();
+                layer()
+            })
+        });
+        set_rx.recv().unwrap();
+        // The most recently set value is current on every thread.
+        assert_eq!(layer(), Some(2));
+        assert_eq!(GlobalCurrent::<Layer>::depth(), 2);
+        done_tx.send(()).unwrap();
+        assert_eq!(worker.join().unwrap(), Some(2));
+        assert_eq!(layer(), Some(1));
+    });
+    assert_eq!(layer(), None);
+}
+
+#[test]
+fn global_storage_borrows_mutably() {
+    let mut gauge = Gauge(1);
+    CurrentGuard::scope_in(&Global, &mut gauge, || {
+        thread::spawn(|| {
+            Current::<Gauge, (), Global>::with_mut(|gauge| gauge.0 += 1)
+        }).join().unwrap();
+        assert_eq!(GlobalCurrent::<Gauge>::with(|gauge| gauge.0), Some(2));
+    });
+    assert_eq!(gauge.0, 2);
+}
+
+#[test]
+fn global_guard_waits_for_borrows() {
+    let done = Done(AtomicBool::new(false));
+    let (borrowed_tx, borrowed_rx) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        while GlobalCurrent::<Done>::with(|done| {
+            borrowed_tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            done.0.store(true, Ordering::SeqCst);
+        }).is_none() {}
+    });
+    GlobalCurrentGuard::scope(&done, || borrowed_rx.recv().unwrap());
+    // Dropping the guard waited for the reader to finish with the value.
+    assert!(done.0.load(Ordering::SeqCst));
+    reader.join().unwrap();
+}
+
+#[test]
+#[should_panic(expected = "process-global map")]
+fn global_map_only_set_through_global() {
+    Global.with_map(|map| map.scope(&mut Gauge(1), || ()));
+}
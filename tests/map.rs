@@ -0,0 +1,115 @@
+extern crate current;
+
+use current::{
+    Context, ContextMap, Current, CurrentBox, CurrentBuilder, CurrentGuard,
+    CurrentGuards, CurrentStorage, GuardStack, SharedGuard, Stores
+};
+
+struct Audio {
+    volume: u8
+}
+
+thread_local!(static MIXER: ContextMap = ContextMap::new());
+
+#[derive(Default)]
+struct Mixer;
+
+impl CurrentStorage for Mixer {
+    fn with_map<F, R>(&self, f: F) -> Option<R>
+        where F: FnOnce(&ContextMap) -> R
+    {
+        Some(MIXER.with(f))
+    }
+}
+
+impl<T> Stores<T> for Mixer where T: ?Sized {}
+
+fn mixer_volume() -> Option<u8> {
+    Current::<Audio, (), Mixer>::with(|audio| audio.volume)
+}
+
+#[test]
+fn map_is_separate_from_thread() {
+    let map = ContextMap::new();
+    let mut audio = Audio { volume: 3 };
+    let mut local = Audio { volume: 9 };
+    map.scope(&mut audio, || {
+        assert_eq!(map.with(|audio: &Audio| audio.volume), Some(3));
+        assert_eq!(Current::<Audio>::with(|audio| audio.volume), None);
+        CurrentGuard::scope(&mut local, || {
+            map.with_mut(|audio: &mut Audio| audio.volume += 1);
+            assert_eq!(map.depth::<Audio>(), 1);
+        });
+        let context = Context::capture_in(&map);
+        map.scope(&mut local, || {
+            assert_eq!(map.depth::<Audio>(), 2);
+            context.enter(|| {
+                assert_eq!(map.with(|audio: &Audio| audio.volume), Some(4));
+            });
+        });
+    });
+    assert!(map.try_with(|_: &Audio| ()).unwrap_err().was_removed());
+    assert_eq!(audio.volume, 4);
+}
+
+#[test]
+#[should_panic(expected = "is already borrowed")]
+fn map_borrow_rules() {
+    let map = ContextMap::new();
+    let mut audio = Audio { volume: 3 };
+    map.scope(&mut audio, || {
+        map.with(|_: &Audio| {
+            map.with_mut(|audio: &mut Audio| audio.volume = 0)
+        });
+    });
+}
+
+#[test]
+fn guards_use_their_storage() {
+    let mut audio = Audio { volume: 1 };
+    let mut count = 0u32;
+    let shared = Audio { volume: 2 };
+    SharedGuard::<_, (), Mixer>::scope_tagged(&shared, || {
+        assert_eq!(mixer_volume(), Some(2));
+        assert_eq!(Current::<Audio>::with(|audio| audio.volume), None);
+    });
+    CurrentGuards::<Mixer>::scope_stored((&mut audio, &mut count), || {
+        assert_eq!(mixer_volume(), Some(1));
+        assert_eq!(Current::<u32>::with(|count| *count), None);
+    });
+    GuardStack::<Mixer>::scope_stored(|stack| {
+        stack.push(&mut audio);
+        assert_eq!(mixer_volume(), Some(1));
+        assert_eq!(Current::<Audio>::with(|audio| audio.volume), None);
+    });
+    CurrentBuilder::<_, Mixer>::new(&mut audio).scope(|| {
+        assert_eq!(mixer_volume(), Some(1));
+    });
+    assert_eq!(mixer_volume(), None);
+}
+
+#[test]
+fn map_backs_guards_and_current() {
+    let map = ContextMap::new();
+    let mut audio = Audio { volume: 1 };
+    let mut count = 0u32;
+    CurrentGuard::scope_in(&map, &mut audio, || {
+        let mut current = unsafe {
+            Current::<Audio, (), ContextMap>::new_in(&map)
+        };
+        current.borrow_mut().volume += 1;
+        assert_eq!(map.with(|audio: &Audio| audio.volume), Some(2));
+        assert_eq!(Current::<Audio>::with(|audio| audio.volume), None);
+    });
+    CurrentGuards::scope_in(&map, (&mut audio, &mut count), || {
+        assert_eq!(map.depth::<Audio>(), 1);
+        assert_eq!(map.with(|count: &u32| *count), Some(0));
+    });
+    GuardStack::scope_in(&map, |stack| {
+        stack.push(&mut count);
+        stack.push_guard(CurrentBox::new_in(&map, 7u32));
+        assert_eq!(map.with(|count: &u32| *count), Some(7));
+    });
+    assert_eq!(map.depth::<u32>(), 0);
+    assert_eq!(audio.volume, 2);
+}
@@ -0,0 +1,58 @@
+extern crate current;
+
+use current::{ Current, CurrentGuard, Currents };
+use current::{ spawn_scoped_with_currents, spawn_with_currents };
+use std::sync::Arc;
+use std::thread;
+
+#[derive(Clone)]
+struct Settings {
+    verbose: bool
+}
+
+struct Log;
+
+#[test]
+fn spawn_with_cloned_current() {
+    let mut settings = Settings { verbose: true };
+    let currents = CurrentGuard::scope(&mut settings, || {
+        Currents::new().clone_current::<Settings>()
+    });
+    let verbose = spawn_with_currents(currents, || {
+        Current::<Settings>::with(|settings| settings.verbose)
+    }).join().unwrap();
+    assert_eq!(verbose, Some(true));
+}
+
+#[test]
+fn spawn_scoped_with_shared_current() {
+    let names = Arc::new(vec!["a", "b"]);
+    let mut shared = names.clone();
+    CurrentGuard::scope(&mut shared, || {
+        thread::scope(|s| {
+            let currents = Currents::new()
+                .clone_current::<Arc<Vec<&'static str>>>()
+                .value_tagged::<&'static str, Log>("worker");
+            let handle = spawn_scoped_with_currents(s, currents, || {
+                let len = Current::<Arc<Vec<&'static str>>>::with(|names| {
+                    names.len()
+                });
+                (len, Current::<&'static str, Log>::with(|log| *log))
+            });
+            assert_eq!(handle.join().unwrap(), (Some(2), Some("worker")));
+        });
+    });
+    assert_eq!(Arc::strong_count(&names), 2);
+}
+
+#[test]
+fn currents_restored_after_scope() {
+    Currents::new().value(1u8).value(2u8).scope(|| {
+        assert_eq!(Current::<u8>::depth(), 2);
+        assert_eq!(Current::<u8>::with(|val| *val), Some(2));
+    });
+    assert_eq!(Current::<u8>::depth(), 0);
+    assert!(Currents::new().clone_current::<Settings>().scope(|| {
+        Current::<Settings>::with(|_| ()).is_none()
+    }));
+}
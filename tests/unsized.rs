@@ -0,0 +1,120 @@
+extern crate current;
+
+use current::{ Current, CurrentBox, CurrentGuard };
+
+trait Renderer {
+    fn name(&self) -> &str;
+    fn draw(&mut self);
+}
+
+struct Gl {
+    draws: u32
+}
+
+impl Renderer for Gl {
+    fn name(&self) -> &str { "gl" }
+    fn draw(&mut self) { self.draws += 1; }
+}
+
+fn draw() {
+    Current::<dyn Renderer>::with_mut(|renderer| renderer.draw());
+}
+
+#[test]
+fn trait_object_current() {
+    let mut gl = Gl { draws: 0 };
+    CurrentGuard::<dyn Renderer>::scope(&mut gl, || {
+        draw();
+        draw();
+        assert_eq!(Current::<dyn Renderer>::with(|r| r.name().to_string()),
+                   Some("gl".to_string()));
+        assert_eq!(Current::<Gl>::with(|gl| gl.draws), None);
+    });
+    assert_eq!(gl.draws, 2);
+}
+
+#[test]
+fn boxed_trait_object_current() {
+    let boxed = CurrentBox::<dyn Renderer>::from_box(Box::new(Gl { draws: 0 }));
+    draw();
+    assert_eq!(boxed.into_box().name(), "gl");
+    assert_eq!(Current::<dyn Renderer>::with(|_| ()), None);
+}
+
+#[test]
+fn slice_current() {
+    let mut buf = [1u8, 2, 3];
+    CurrentGuard::<[u8]>::scope(&mut buf[..], || {
+        Current::<[u8]>::with_mut(|buf| buf.reverse());
+        assert_eq!(Current::<[u8]>::with(|buf| buf.len()), Some(3));
+    });
+    assert_eq!(buf, [3, 2, 1]);
+}
+
+trait Window {
+    fn title(&self) -> String;
+}
+
+trait InputSource {
+    fn poll(&mut self) -> Option<u32>;
+}
+
+struct GlWindow {
+    title: String,
+    events: Vec<u32>
+}
+
+impl Window for GlWindow {
+    fn title(&self) -> String { self.title.clone() }
+}
+
+impl InputSource for GlWindow {
+    fn poll(&mut self) -> Option<u32> { self.events.pop() }
+}
+
+#[test]
+fn views_are_current_together() {
+    let mut window = GlWindow { title: "main".to_string(), events: vec![1, 2] };
+    CurrentGuard::builder(&mut window)
+        .view::<dyn Window>(|w| w)
+        .view::<dyn InputSource>(|w| w)
+        .scope(|| {
+            assert_eq!(Current::<dyn Window>::with(|w| w.title()),
+                       Some("main".to_string()));
+            assert_eq!(Current::<dyn InputSource>::with_mut(|i| i.poll()),
+                       Some(Some(2)));
+            Current::<GlWindow>::with_mut(|w| w.title.push('!'));
+        });
+    assert_eq!(window.title, "main!");
+    assert_eq!(window.events, vec![1]);
+    assert_eq!(Current::<dyn Window>::with(|_| ()), None);
+    assert_eq!(Current::<dyn InputSource>::with(|_| ()), None);
+}
+
+#[test]
+fn views_follow_reallocation() {
+    let mut vals: Vec<u64> = vec![1];
+    CurrentGuard::builder(&mut vals)
+        .view::<[u64]>(|vals| &mut vals[..])
+        .scope(|| {
+            Current::<Vec<u64>>::with_mut(|vals| vals.extend(2..100));
+            let sum = Current::<[u64]>::with(|vals| vals.iter().sum::<u64>());
+            assert_eq!(sum, Some(4950));
+            Current::<[u64]>::with_mut(|vals| vals[0] = 0);
+        });
+    assert_eq!(vals.len(), 99);
+    assert_eq!(vals[0], 0);
+}
+
+#[test]
+#[should_panic(expected = "is already mutably borrowed")]
+fn views_share_borrows() {
+    let mut window = GlWindow { title: "main".to_string(), events: vec![] };
+    CurrentGuard::builder(&mut window)
+        .view::<dyn Window>(|w| w)
+        .scope(|| {
+            Current::<GlWindow>::with_mut(|_| {
+                Current::<dyn Window>::with(|w| w.title());
+            });
+        });
+}